@@ -46,6 +46,16 @@ pub enum ChatType {
     PublicChannel,
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "workspace_role", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum WorkspaceRole {
+    Owner,
+    Admin,
+    Member,
+    Guest,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
 pub struct Chat {
     pub id: i64,
@@ -53,7 +63,34 @@ pub struct Chat {
     pub name: Option<String>,
     pub r#type: ChatType,
     pub members: Vec<i64>,
+    /// what is being discussed at the moment
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    /// an uploaded file, e.g. /files/1/1e2/078/862bd199a443a09348c11e463c80527905.png
+    pub icon: Option<String>,
+    /// archived chats are read-only
+    pub archived_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
+    /// read position of the current user, only filled when listing chats
+    #[sqlx(default)]
+    #[serde(default)]
+    pub last_read_id: i64,
+    /// unread messages of the main timeline, thread replies aren't counted
+    #[sqlx(default)]
+    #[serde(default)]
+    pub unread_count: i64,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub mention_count: i64,
+}
+
+/// How far a member has read a chat
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
+pub struct ChatRead {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub last_read_id: i64,
+    pub updated_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
@@ -64,6 +101,113 @@ pub struct Message {
     pub content: String,
     pub files: Vec<String>,
     pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub deleted_by: Option<i64>,
+    /// the top level message this one replies to
+    pub parent_id: Option<i64>,
+    pub reply_count: i32,
+    pub last_reply_at: Option<DateTime<Utc>>,
+    /// filled in when listing messages
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub reactions: Vec<ReactionCount>,
+    /// people addressed by the message, validated at send time
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub mentions: Vec<Mention>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "mention_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MentionKind {
+    User,
+    Channel,
+    /// an alias of `@channel`, everyone in the chat is notified whether online or not
+    Here,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, Eq, Hash, ToSchema)]
+pub struct Mention {
+    pub kind: MentionKind,
+    /// only set for user mentions
+    pub user_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
+pub struct Reaction {
+    pub message_id: i64,
+    pub user_id: i64,
+    pub emoji: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Reactions on a message grouped by emoji
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+    pub users: Vec<i64>,
+}
+
+impl Message {
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// Drop the content of a deleted message, only the tombstone is left to render
+    pub fn into_tombstone(mut self) -> Self {
+        if self.is_deleted() {
+            self.content.clear();
+            self.files.clear();
+            self.reactions.clear();
+            self.mentions.clear();
+        }
+        self
+    }
+}
+
+/// Actions which are restricted by workspace role
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    ManageWorkspace,
+    ManageMembers,
+    InviteMembers,
+    CreateChat,
+    /// joining public channels, guests only take part in the chats they are added to
+    JoinChannel,
+    ManageChat,
+    /// archived chats go read-only for everyone in them
+    ArchiveChat,
+    DeleteChat,
+    DeleteAnyMessage,
+}
+
+impl WorkspaceRole {
+    pub fn can(&self, permission: Permission) -> bool {
+        use Permission::*;
+        match self {
+            WorkspaceRole::Owner => true,
+            WorkspaceRole::Admin => permission != ManageWorkspace,
+            WorkspaceRole::Member => matches!(permission, CreateChat | JoinChannel | ManageChat),
+            WorkspaceRole::Guest => false,
+        }
+    }
+
+    /// Whether this role may manage people holding the other role
+    pub fn outranks(&self, other: WorkspaceRole) -> bool {
+        self.rank() > other.rank()
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            WorkspaceRole::Owner => 3,
+            WorkspaceRole::Admin => 2,
+            WorkspaceRole::Member => 1,
+            WorkspaceRole::Guest => 0,
+        }
+    }
 }
 
 impl User {
@@ -83,6 +227,25 @@ impl User {
 #[cfg(test)]
 mod tests {
 
+    use super::*;
+
     #[test]
     fn core_test() {}
+
+    #[test]
+    fn workspace_role_should_check_permissions() {
+        assert!(WorkspaceRole::Owner.can(Permission::ManageWorkspace));
+        assert!(!WorkspaceRole::Admin.can(Permission::ManageWorkspace));
+        assert!(WorkspaceRole::Admin.can(Permission::DeleteChat));
+        assert!(!WorkspaceRole::Member.can(Permission::DeleteChat));
+        assert!(WorkspaceRole::Admin.can(Permission::ArchiveChat));
+        assert!(!WorkspaceRole::Member.can(Permission::ArchiveChat));
+        assert!(WorkspaceRole::Member.can(Permission::CreateChat));
+        assert!(!WorkspaceRole::Guest.can(Permission::CreateChat));
+        assert!(WorkspaceRole::Member.can(Permission::JoinChannel));
+        assert!(!WorkspaceRole::Guest.can(Permission::JoinChannel));
+
+        assert!(WorkspaceRole::Admin.outranks(WorkspaceRole::Member));
+        assert!(!WorkspaceRole::Admin.outranks(WorkspaceRole::Admin));
+    }
 }
@@ -1,4 +1,5 @@
 use super::TokenVerify;
+use crate::{READ_SCOPE, WRITE_SCOPE};
 use axum::{
     extract::{FromRequestParts, Query, Request, State},
     http::StatusCode,
@@ -44,7 +45,13 @@ where
             }
         };
 
-    let req = match state.verify(&token) {
+    // reading needs the read scope, anything else the write scope
+    let scope = if pairs.method.is_safe() {
+        READ_SCOPE
+    } else {
+        WRITE_SCOPE
+    };
+    let req = match state.verify(&token, scope).await {
         Ok(user) => {
             let mut req = Request::from_parts(pairs, body);
             req.extensions_mut().insert(user);
@@ -68,7 +75,7 @@ mod tests {
     use crate::User;
 
     use super::*;
-    use crate::{DecodingKey, EncodingKey};
+    use crate::{DecodingKey, EncodingKey, TokenType};
     use anyhow::Result;
     use axum::{body::Body, middleware::from_fn_with_state, routing::get, Router};
     use tower::ServiceExt;
@@ -84,8 +91,14 @@ mod tests {
     impl TokenVerify for AppState {
         type Error = ();
 
-        fn verify(&self, token: &str) -> std::result::Result<User, Self::Error> {
-            self.0.dk.verify(token).map_err(|_| ())
+        async fn verify(&self, token: &str, scope: &str) -> std::result::Result<User, Self::Error> {
+            match self.0.dk.verify(token) {
+                // session 0 stands in for a revoked session
+                Ok(claims) if claims.session_id != 0 && claims.has_scope(scope) => {
+                    Ok(User::new(claims.user_id, "alice", "alice@qq.com"))
+                }
+                _ => Err(()),
+            }
         }
     }
     #[tokio::test]
@@ -98,10 +111,15 @@ mod tests {
         let state = AppState(Arc::new(AppStateInner { ek, dk }));
 
         let user = User::new(1, "alice", "alice@qq.com");
-        let token = state.0.ek.sign(user)?;
+        let token = state.0.ek.sign(user.clone(), 1)?;
+        let revoked_token = state.0.ek.sign(user.clone(), 0)?;
+        let read_token = state
+            .0
+            .ek
+            .sign_with_scopes(user, 1, TokenType::Access, &[READ_SCOPE])?;
 
         let app = Router::new()
-            .route("/", get(handler))
+            .route("/", get(handler).post(handler))
             .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
             .with_state(state);
 
@@ -126,6 +144,29 @@ mod tests {
         let res = app.clone().oneshot(req).await?;
         assert_eq!(res.status(), StatusCode::FORBIDDEN);
 
+        // revoked session
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", format!("Bearer {revoked_token}"))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // read-only token may read but not write
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", format!("Bearer {read_token}"))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let req = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header("Authorization", format!("Bearer {read_token}"))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
         // good token
         let req = Request::builder()
             .uri(format!("/?access_token={token}"))
@@ -2,7 +2,7 @@ mod auth;
 mod request_id;
 mod server_time;
 
-use core::fmt;
+use core::{fmt, future::Future};
 
 use crate::User;
 pub use auth::verify_token;
@@ -23,7 +23,12 @@ const SERVER_TIME_HEADER: &str = "x-server-time";
 pub trait TokenVerify {
     type Error: fmt::Debug;
 
-    fn verify(&self, token: &str) -> Result<User, Self::Error>;
+    /// Verify the token grants `scope` and make sure the session it belongs to is still active
+    fn verify(
+        &self,
+        token: &str,
+        scope: &str,
+    ) -> impl Future<Output = Result<User, Self::Error>> + Send;
 }
 
 pub fn set_layer(app: Router) -> Router {
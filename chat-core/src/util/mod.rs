@@ -1,3 +1,8 @@
 mod jwt;
+mod session;
 
-pub use jwt::{DecodingKey, EncodingKey};
+pub use jwt::{
+    DecodingKey, EncodingKey, Jwk, Jwks, TokenClaims, TokenType, CHAT_AUDIENCE, NOTIFY_AUDIENCE,
+    READ_SCOPE, WRITE_SCOPE,
+};
+pub use session::find_session_user;
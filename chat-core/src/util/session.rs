@@ -0,0 +1,18 @@
+use crate::{TokenClaims, User};
+use sqlx::PgPool;
+
+/// Load the user behind verified token claims, as long as its session is still active
+/// and the user is still a member of the workspace the token is scoped to
+pub async fn find_session_user(
+    pool: &PgPool,
+    claims: &TokenClaims,
+) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT u.id, w.id AS ws_id, w.name AS ws_name, u.fullname, u.email, u.created_at FROM sessions s JOIN users u ON u.id = s.user_id JOIN workspace_members m ON m.user_id = u.id AND m.ws_id = s.ws_id JOIN workspaces w ON w.id = m.ws_id WHERE s.id = $1 AND s.user_id = $2 AND s.ws_id = $3 AND s.revoked_at IS NULL AND s.expires_at > NOW()",
+    )
+    .bind(claims.session_id)
+    .bind(claims.user_id)
+    .bind(claims.ws_id)
+    .fetch_optional(pool)
+    .await
+}
@@ -1,12 +1,18 @@
 use std::{env, fs::File, path::PathBuf};
 
-use anyhow::{bail, Result};
+use crate::mailer::MailTransport;
+use anyhow::{bail, Context, Result};
+use chat_core::{DecodingKey, EncodingKey};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChatConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub mailer: MailerConfig,
+    #[serde(default)]
+    pub message: MessageConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,6 +26,52 @@ pub struct ServerConfig {
 pub struct AuthConfig {
     pub sk: String,
     pub pk: String,
+    /// key id of the signing key, derived from pk if not set
+    #[serde(default)]
+    pub kid: Option<String>,
+    /// retired or upcoming public keys which are still trusted for verification
+    #[serde(default)]
+    pub trusted_keys: Vec<TrustedKey>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrustedKey {
+    pub kid: String,
+    pub pk: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MailerConfig {
+    pub from: String,
+    /// where the links in mails point to
+    pub app_url: String,
+    pub transport: MailTransport,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageConfig {
+    /// how long after sending a message its sender may still edit it
+    pub edit_window_secs: u64,
+}
+
+impl Default for MessageConfig {
+    fn default() -> Self {
+        Self {
+            edit_window_secs: 15 * 60,
+        }
+    }
+}
+
+impl Default for MailerConfig {
+    fn default() -> Self {
+        Self {
+            from: "Chat <noreply@localhost>".to_string(),
+            app_url: "http://localhost:6688".to_string(),
+            transport: MailTransport::File {
+                dir: PathBuf::from("tmp/mails"),
+            },
+        }
+    }
 }
 
 impl ChatConfig {
@@ -39,3 +91,28 @@ impl ChatConfig {
         Ok(ret?)
     }
 }
+
+impl AuthConfig {
+    /// Load the signing key and every public key tokens may be verified with
+    pub fn load_keys(&self) -> Result<(EncodingKey, DecodingKey)> {
+        let mut ek = EncodingKey::load(&self.sk).context("load sk failed")?;
+        if let Some(kid) = &self.kid {
+            ek = ek.with_key_id(kid);
+        }
+
+        // tokens signed with sk couldn't be verified otherwise
+        if !ek.matches(&self.pk).context("load pk failed")? {
+            bail!("auth.pk is not the public key of auth.sk");
+        }
+
+        let mut dk = DecodingKey::default();
+        dk.add_key(ek.key_id(), &self.pk)
+            .context("load pk failed")?;
+        for key in &self.trusted_keys {
+            dk.add_key(&key.kid, &key.pk)
+                .with_context(|| format!("load trusted key {} failed", key.kid))?;
+        }
+
+        Ok((ek, dk))
+    }
+}
@@ -15,10 +15,22 @@ pub enum AppError {
     #[error("email already exists: {0}")]
     EmailAlreadyExists(String),
 
+    #[error("workspace already exists: {0}, ask its owner for an invitation")]
+    WorkspaceAlreadyExists(String),
+
+    #[error("update workspace error: {0}")]
+    UpdateWorkspaceError(String),
+
+    #[error("invite error: {0}")]
+    InviteError(String),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
     #[error("create chat error: {0}")]
     CreateChatError(String),
 
-    #[error("create chat error: {0}")]
+    #[error("update chat error: {0}")]
     UpdateChatError(String),
 
     #[error("{0}")]
@@ -27,6 +39,12 @@ pub enum AppError {
     #[error("create message error: {0}")]
     CreateMessageError(String),
 
+    #[error("update message error: {0}")]
+    UpdateMessageError(String),
+
+    #[error("reaction error: {0}")]
+    ReactionError(String),
+
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
@@ -39,6 +57,18 @@ pub enum AppError {
     #[error("password hash error: {0}")]
     PasswordHashError(#[from] argon2::password_hash::Error),
 
+    #[error("invalid refresh token")]
+    InvalidRefreshToken,
+
+    #[error("session has been revoked or expired")]
+    SessionRevoked,
+
+    #[error("invalid or expired token")]
+    InvalidUserToken,
+
+    #[error("send mail error: {0}")]
+    MailError(String),
+
     #[error("jwt error: {0}")]
     JwtError(#[from] jwt_simple::Error),
 
@@ -58,13 +88,23 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response<axum::body::Body> {
         let status = match self {
             AppError::EmailAlreadyExists(_) => StatusCode::CONFLICT,
+            AppError::WorkspaceAlreadyExists(_) => StatusCode::CONFLICT,
+            AppError::UpdateWorkspaceError(_) => StatusCode::BAD_REQUEST,
+            AppError::InviteError(_) => StatusCode::BAD_REQUEST,
+            AppError::PermissionDenied(_) => StatusCode::FORBIDDEN,
             AppError::CreateChatError(_) => StatusCode::BAD_REQUEST,
             AppError::UpdateChatError(_) => StatusCode::BAD_REQUEST,
             AppError::ChatFileError(_) => StatusCode::BAD_REQUEST,
             AppError::CreateMessageError(_) => StatusCode::BAD_REQUEST,
+            AppError::UpdateMessageError(_) => StatusCode::BAD_REQUEST,
+            AppError::ReactionError(_) => StatusCode::BAD_REQUEST,
             AppError::NotFound(_) => StatusCode::NOT_FOUND,
             AppError::SqlxError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::PasswordHashError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::InvalidRefreshToken => StatusCode::FORBIDDEN,
+            AppError::SessionRevoked => StatusCode::FORBIDDEN,
+            AppError::InvalidUserToken => StatusCode::BAD_REQUEST,
+            AppError::MailError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::JwtError(_) => StatusCode::FORBIDDEN,
             AppError::HttpHeaderError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             AppError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -1,14 +1,24 @@
 use crate::{
-    models::{CreateUser, SigninUser},
+    models::{CreateUser, ForgotPassword, RefreshToken, ResetPassword, SigninUser, VerifyEmail},
     AppError, AppState, ErrorOutput,
 };
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{Jwks, User};
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 use utoipa::ToSchema;
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct AuthOutput {
     token: String,
+    /// for notify-server, chat-server doesn't accept it
+    notify_token: String,
+    refresh_token: String,
 }
 
 #[utoipa::path(
@@ -23,8 +33,11 @@ pub(crate) async fn signup_handler(
     Json(input): Json<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.create_user(&input).await?;
-    let token = state.ek.sign(user)?;
-    let body = Json(AuthOutput { token });
+    // the account is usable right away, a failed mail can be resent later
+    if let Err(e) = state.send_verification_email(&user).await {
+        warn!("send verification email to {} failed: {}", user.email, e);
+    }
+    let body = Json(start_session(&state, user).await?);
     Ok((StatusCode::OK, body))
 }
 
@@ -43,8 +56,8 @@ pub(crate) async fn signin_handler(
 
     match user {
         Some(user) => {
-            let token = state.ek.sign(user)?;
-            Ok((StatusCode::OK, Json(AuthOutput { token })).into_response())
+            let body = Json(start_session(&state, user).await?);
+            Ok((StatusCode::OK, body).into_response())
         }
         None => {
             let body = Json(ErrorOutput::new("Invalid email or password"));
@@ -53,30 +66,217 @@ pub(crate) async fn signin_handler(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/refresh",
+    responses(
+        (status = 200, description = "Tokens refreshed", body = AuthOutput)
+    )
+)]
+pub(crate) async fn refresh_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RefreshToken>,
+) -> Result<impl IntoResponse, AppError> {
+    let (session, refresh_token) = state.rotate_session(&input.refresh_token).await?;
+    let Some(mut user) = state.find_user_by_id(session.user_id as _).await? else {
+        return Err(AppError::NotFound("user not found".to_string()));
+    };
+    user.ws_id = session.ws_id;
+
+    let body = Json(issue_tokens(&state, user, session.id, refresh_token)?);
+    Ok((StatusCode::OK, body))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/workspaces/{id}/switch",
+    params(
+        ("id" = u64, Path, description = "Workspace id")
+    ),
+    responses(
+        (status = 200, description = "Tokens scoped to the workspace", body = AuthOutput),
+        (status = 403, description = "Not a member of the workspace", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn switch_workspace_handler(
+    State(state): State<AppState>,
+    Path(ws_id): Path<u64>,
+    Json(input): Json<RefreshToken>,
+) -> Result<impl IntoResponse, AppError> {
+    // check membership first so a failed switch doesn't burn the refresh token
+    let Some(session) = state.find_session_by_token(&input.refresh_token).await? else {
+        return Err(AppError::InvalidRefreshToken);
+    };
+    state
+        .find_member_workspace(ws_id, session.user_id as _)
+        .await?;
+    // other sessions of the user stay where they are
+    let (session, refresh_token) = state.switch_session(&input.refresh_token, ws_id).await?;
+    // only a switch that went through becomes the default for new sessions
+    state
+        .set_default_workspace(session.user_id as _, ws_id)
+        .await?;
+    let Some(mut user) = state.find_user_by_id(session.user_id as _).await? else {
+        return Err(AppError::NotFound("user not found".to_string()));
+    };
+    user.ws_id = session.ws_id;
+
+    let body = Json(issue_tokens(&state, user, session.id, refresh_token)?);
+    Ok((StatusCode::OK, body))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/signout",
+    responses(
+        (status = 204, description = "Session revoked")
+    )
+)]
+pub(crate) async fn signout_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RefreshToken>,
+) -> Result<impl IntoResponse, AppError> {
+    state.revoke_session_by_token(&input.refresh_token).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/email/verify",
+    responses(
+        (status = 204, description = "Email verified"),
+        (status = 400, description = "Invalid or expired token", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn verify_email_handler(
+    State(state): State<AppState>,
+    Json(input): Json<VerifyEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    state.verify_email(&input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/email/verification",
+    responses(
+        (status = 204, description = "Verification email sent")
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn resend_verification_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.send_verification_email(&user).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/password/forgot",
+    responses(
+        (status = 204, description = "Reset email sent if the account exists")
+    )
+)]
+pub(crate) async fn forgot_password_handler(
+    State(state): State<AppState>,
+    Json(input): Json<ForgotPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    state.send_password_reset_email(&input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/password/reset",
+    responses(
+        (status = 204, description = "Password changed, every session signed out"),
+        (status = 400, description = "Invalid or expired token", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn reset_password_handler(
+    State(state): State<AppState>,
+    Json(input): Json<ResetPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    state.reset_password(&input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    responses(
+        (status = 200, description = "Public keys tokens are signed with", body = Jwks)
+    )
+)]
+pub(crate) async fn jwks_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.dk.jwks())
+}
+
+async fn start_session(state: &AppState, user: User) -> Result<AuthOutput, AppError> {
+    let (session, refresh_token) = state.create_session(user.id as _, user.ws_id as _).await?;
+    issue_tokens(state, user, session.id, refresh_token)
+}
+
+/// One token per service, so neither can be replayed against the other
+fn issue_tokens(
+    state: &AppState,
+    user: User,
+    session_id: i64,
+    refresh_token: String,
+) -> Result<AuthOutput, AppError> {
+    Ok(AuthOutput {
+        token: state.ek.sign(user.clone(), session_id)?,
+        notify_token: state.ek.sign_notify(user, session_id)?,
+        refresh_token,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{mailer::MemoryMailer, models::CreateInvite};
     use anyhow::Result;
+    use chat_core::WorkspaceRole;
+    use chat_core::{TokenType, TokenVerify, READ_SCOPE};
     use http_body_util::BodyExt;
+    use std::sync::Arc;
+
+    fn token_from_mail(body: &str) -> &str {
+        let start = body.find("token=").expect("mail should contain a token") + 6;
+        body[start..].split_whitespace().next().unwrap()
+    }
 
     #[tokio::test]
     async fn signup_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("none", "nyh", "nyh@qq.com", "nyh1111");
-        let ret = signup_handler(State(state), Json(input))
+        let input = CreateUser::new("nyh", "nyh", "nyh@qq.com", "nyh1111");
+        let ret = signup_handler(State(state.clone()), Json(input))
             .await?
             .into_response();
         assert_eq!(ret.status(), StatusCode::OK);
         let body = ret.into_body().collect().await?.to_bytes();
         let ret: AuthOutput = serde_json::from_slice(&body)?;
         assert_ne!(ret.token, "");
+
+        // each token is only good for its own service
+        let notify_dk = state.dk.clone().with_token_type(TokenType::Notify);
+        assert!(state.dk.verify(&ret.notify_token).is_err());
+        assert!(notify_dk.verify(&ret.token).is_err());
+        assert_eq!(
+            notify_dk.verify(&ret.notify_token)?.user_id,
+            state.dk.verify(&ret.token)?.user_id
+        );
         Ok(())
     }
 
     #[tokio::test]
     async fn signup_already_exists_user_should_409() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("none", "alice", "alice@qq.com", "alice123");
+        let input = CreateUser::new("alice", "alice", "alice@qq.com", "alice123");
         signup_handler(State(state.clone()), Json(input.clone())).await?;
 
         let ret = signup_handler(State(state), Json(input))
@@ -96,7 +296,7 @@ mod tests {
         let name = "Alice";
         let email = "alice@qq.com";
         let password = "alice123";
-        let user = CreateUser::new("none", name, email, password);
+        let user = CreateUser::new("alice", name, email, password);
         state.create_user(&user).await?;
         let input = SigninUser::new(email, password);
         let ret = signin_handler(State(state), Json(input))
@@ -125,4 +325,186 @@ mod tests {
         assert_eq!(ret.error, "Invalid email or password");
         Ok(())
     }
+
+    #[tokio::test]
+    async fn jwks_should_publish_signing_key() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = jwks_handler(State(state.clone())).await.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: Jwks = serde_json::from_slice(&body)?;
+        assert!(ret.keys.iter().any(|k| k.kid == state.ek.key_id()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_should_rotate_tokens() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let auth = start_session(&state, user).await?;
+
+        let input = RefreshToken::new(&auth.refresh_token);
+        let ret = refresh_handler(State(state.clone()), Json(input.clone()))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        assert_ne!(ret.refresh_token, auth.refresh_token);
+
+        // the old refresh token is single use
+        let ret = refresh_handler(State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn switch_workspace_should_scope_token() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        let auth = start_session(&state, user.clone()).await?;
+        let other = start_session(&state, user.clone()).await?;
+
+        // not a member of foo yet
+        let input = RefreshToken::new(&auth.refresh_token);
+        let ret = switch_workspace_handler(State(state.clone()), Path(2), Json(input.clone()))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        // owner of foo invites the user
+        let foo = CreateUser::new("foo2", "foo", "foo@qq.com", "foo123");
+        let foo_owner = state.create_user(&foo).await?;
+        let ret = state
+            .create_invite(&foo_owner, WorkspaceRole::Owner, &CreateInvite::default())
+            .await?;
+        let ws = state.join_workspace(&user, &ret.code).await?;
+        assert_eq!(ws.name, "foo2");
+        let workspaces = state.fetch_user_workspaces(user.id as _).await?;
+        assert_eq!(workspaces.len(), 2);
+
+        let ret =
+            switch_workspace_handler(State(state.clone()), Path(ws.id as _), Json(input.clone()))
+                .await?
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        let user = state.verify(&ret.token, READ_SCOPE).await?;
+        assert_eq!(user.ws_id, ws.id);
+        assert_eq!(user.ws_name, "foo2");
+
+        // a rotated token can't switch back, nor move the default workspace
+        let ret = switch_workspace_handler(State(state.clone()), Path(1), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        let ret = state.find_user_by_id(user.id as _).await?.unwrap();
+        assert_eq!(ret.ws_id, ws.id);
+        // access tokens of the session from before the switch are done
+        let ret = state.verify(&auth.token, READ_SCOPE).await;
+        assert!(matches!(ret, Err(AppError::SessionRevoked)));
+
+        // the chats of acme are out of scope now
+        let users = state.fetch_chat_user_all(user.ws_id as _).await?;
+        assert_eq!(users.len(), 2);
+        assert!(!users.iter().any(|u| u.id == owner.id));
+
+        // the other session keeps its workspace when refreshed
+        let input = RefreshToken::new(&other.refresh_token);
+        let ret = refresh_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        let user = state.verify(&ret.token, READ_SCOPE).await?;
+        assert_eq!(user.ws_name, "acme");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signout_should_revoke_access_token() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let auth = start_session(&state, user).await?;
+        let user = state.verify(&auth.token, READ_SCOPE).await?;
+        assert_eq!(user.id, 1);
+        assert_eq!(user.ws_name, "acme");
+
+        let input = RefreshToken::new(&auth.refresh_token);
+        let ret = signout_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+
+        let ret = state.verify(&auth.token, READ_SCOPE).await;
+        assert!(matches!(ret, Err(AppError::SessionRevoked)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signup_should_send_verification_email() -> Result<()> {
+        let mailer = MemoryMailer::default();
+        let (_tdb, state) = AppState::new_for_test_with_mailer(Arc::new(mailer.clone())).await?;
+        let input = CreateUser::new("nyh", "nyh", "nyh@qq.com", "nyh1111");
+        signup_handler(State(state.clone()), Json(input)).await?;
+
+        let outbox = mailer.outbox();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].to, "nyh@qq.com");
+
+        let input = VerifyEmail {
+            token: token_from_mail(&outbox[0].body).to_string(),
+        };
+        let ret = verify_email_handler(State(state.clone()), Json(input.clone()))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+
+        let ret = verify_email_handler(State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reset_password_should_revoke_sessions() -> Result<()> {
+        let mailer = MemoryMailer::default();
+        let (_tdb, state) = AppState::new_for_test_with_mailer(Arc::new(mailer.clone())).await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let auth = start_session(&state, user.clone()).await?;
+
+        // unknown emails get the same answer but no mail
+        let input = ForgotPassword {
+            email: "nobody@qq.com".to_string(),
+        };
+        let ret = forgot_password_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        assert!(mailer.outbox().is_empty());
+
+        let input = ForgotPassword {
+            email: user.email.clone(),
+        };
+        forgot_password_handler(State(state.clone()), Json(input)).await?;
+        let outbox = mailer.outbox();
+        assert_eq!(outbox.len(), 1);
+
+        let input = ResetPassword::new(token_from_mail(&outbox[0].body), "new-password");
+        let ret = reset_password_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+
+        let ret = state.verify(&auth.token, READ_SCOPE).await;
+        assert!(matches!(ret, Err(AppError::SessionRevoked)));
+        let input = SigninUser::new(&user.email, "new-password");
+        assert!(state.verify_user(&input).await?.is_some());
+        Ok(())
+    }
 }
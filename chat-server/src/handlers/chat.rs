@@ -1,11 +1,18 @@
-use crate::{models::ParamChat, AppError, AppState};
+use crate::{
+    middlewares::CurrentMember,
+    models::{
+        ChannelSummary, ChatMembers, CreateDirectChat, ListChannels, ListChats, MarkRead,
+        ParamChat, UpdateChatInfo,
+    },
+    AppError, AppState, ErrorOutput,
+};
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
     Extension, Json,
 };
-use chat_core::{Chat, User};
+use chat_core::{Chat, ChatRead, Permission, User};
 
 #[utoipa::path(
     get,
@@ -13,6 +20,9 @@ use chat_core::{Chat, User};
     responses(
         (status = 200, description = "list chats", body = Vec<Chat>)
     ),
+    params(
+        ListChats,
+    ),
     security(
         ("token" = [])
     )
@@ -20,11 +30,66 @@ use chat_core::{Chat, User};
 pub(crate) async fn list_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    Query(input): Query<ListChats>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chats = state.fetch_chat_all(user.ws_id as _, user.id as _).await?;
+    let chats = state
+        .fetch_chat_all(&input, user.ws_id as _, user.id as _)
+        .await?;
     Ok((StatusCode::OK, Json(chats)))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/chats/directory",
+    responses(
+        (status = 200, description = "Public channels of the workspace", body = Vec<ChannelSummary>)
+    ),
+    params(
+        ListChannels,
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_channels_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListChannels>,
+) -> Result<impl IntoResponse, AppError> {
+    let channels = state
+        .list_channels(&input, user.ws_id as _, user.id as _)
+        .await?;
+    Ok(Json(channels))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/join",
+    responses(
+        (status = 200, description = "The channel joined", body = Chat),
+        (status = 403, description = "Guests can't join channels", body = ErrorOutput),
+        (status = 404, description = "No public channel with the id", body = ErrorOutput),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn join_channel_handler(
+    member: CurrentMember,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    member.require(Permission::JoinChannel)?;
+    let user = member.user;
+    let chat = state
+        .join_channel(id, user.ws_id as _, user.id as _)
+        .await?;
+    Ok(Json(chat))
+}
+
 #[utoipa::path(
     get,
     path = "/api/chats/{id}",
@@ -60,10 +125,12 @@ pub(crate) async fn get_chat_handler(
     )
 )]
 pub(crate) async fn create_chat_handler(
-    Extension(user): Extension<User>,
+    member: CurrentMember,
     State(state): State<AppState>,
     Json(input): Json<ParamChat>,
 ) -> Result<impl IntoResponse, AppError> {
+    member.require(Permission::CreateChat)?;
+    let user = member.user;
     let chat = state
         .create_chat(&input, user.id as _, user.ws_id as _)
         .await?;
@@ -71,10 +138,40 @@ pub(crate) async fn create_chat_handler(
 }
 
 #[utoipa::path(
-    patch,
+    post,
+    path = "/api/chats/direct",
+    responses(
+        (status = 200, description = "The existing direct chat", body = Chat),
+        (status = 201, description = "A new direct chat", body = Chat),
+        (status = 400, description = "Not a member of the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn direct_chat_handler(
+    member: CurrentMember,
+    State(state): State<AppState>,
+    Json(input): Json<CreateDirectChat>,
+) -> Result<impl IntoResponse, AppError> {
+    member.require(Permission::CreateChat)?;
+    let user = member.user;
+    let (chat, created) = state
+        .get_or_create_direct_chat(user.ws_id as _, user.id as _, input.user_id)
+        .await?;
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(chat)))
+}
+
+#[utoipa::path(
+    delete,
     path = "/api/chats/{id}",
     responses(
-        (status = 200, description = "update chat", body = Chat)
+        (status = 200, description = "delete chat", body = Chat)
     ),
     params(
         ("id" = u64, Path, description = "chat id"),
@@ -83,23 +180,81 @@ pub(crate) async fn create_chat_handler(
         ("token" = [])
     )
 )]
-pub(crate) async fn update_chat_handler(
-    Extension(user): Extension<User>,
+pub(crate) async fn delete_chat_handler(
+    member: CurrentMember,
     State(state): State<AppState>,
     Path(id): Path<u64>,
-    Json(input): Json<ParamChat>,
 ) -> Result<impl IntoResponse, AppError> {
+    member.require(Permission::DeleteChat)?;
+    let chat = state.delete_chat(id, member.user.ws_id as _).await?;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/chats/{id}/info",
+    responses(
+        (status = 200, description = "Chat with the info updated", body = Chat),
+        (status = 400, description = "Invalid name, text or icon", body = ErrorOutput),
+        (status = 403, description = "Only admins may archive", body = ErrorOutput),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_chat_info_handler(
+    member: CurrentMember,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateChatInfo>,
+) -> Result<impl IntoResponse, AppError> {
+    member.require(Permission::ManageChat)?;
+    if input.archived.is_some() {
+        member.require(Permission::ArchiveChat)?;
+    }
     let chat = state
-        .update_chat(id, user.ws_id as _, &input, user.id as _)
+        .update_chat_info(id, member.user.ws_id as _, &input)
         .await?;
-    Ok((StatusCode::OK, Json(chat)))
+    Ok(Json(chat))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/members",
+    responses(
+        (status = 200, description = "Chat with the members added", body = Chat),
+        (status = 400, description = "Single chat or not a workspace member", body = ErrorOutput),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn add_chat_members_handler(
+    member: CurrentMember,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<ChatMembers>,
+) -> Result<impl IntoResponse, AppError> {
+    member.require(Permission::ManageChat)?;
+    let chat = state
+        .add_chat_members(id, member.user.ws_id as _, &input)
+        .await?;
+    Ok(Json(chat))
 }
 
 #[utoipa::path(
     delete,
-    path = "/api/chats/{id}",
+    path = "/api/chats/{id}/members",
     responses(
-        (status = 200, description = "delete chat", body = Chat)
+        (status = 200, description = "Chat with the members removed", body = Chat),
+        (status = 400, description = "Single chat or too few members left", body = ErrorOutput),
+        (status = 403, description = "Someone to remove doesn't rank lower", body = ErrorOutput),
     ),
     params(
         ("id" = u64, Path, description = "chat id"),
@@ -108,10 +263,241 @@ pub(crate) async fn update_chat_handler(
         ("token" = [])
     )
 )]
-pub(crate) async fn delete_chat_handler(
+pub(crate) async fn remove_chat_members_handler(
+    member: CurrentMember,
     State(state): State<AppState>,
     Path(id): Path<u64>,
+    Json(input): Json<ChatMembers>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.delete_chat(id).await?;
-    Ok((StatusCode::OK, Json(chat)))
+    member.require(Permission::ManageChat)?;
+    // taking yourself out is fine, others have to rank lower
+    let others: Vec<i64> = input
+        .members
+        .iter()
+        .copied()
+        .filter(|id| *id != member.user.id)
+        .collect();
+    state
+        .require_outranks(member.user.ws_id as _, member.role, &others)
+        .await?;
+    let chat = state
+        .remove_chat_members(id, member.user.ws_id as _, &input)
+        .await?;
+    Ok(Json(chat))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/leave",
+    responses(
+        (status = 204, description = "Left the chat, the last one out deletes it"),
+        (status = 400, description = "Single chats can't be left", body = ErrorOutput),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn leave_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.leave_chat(id, user.ws_id as _, user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/read",
+    responses(
+        (status = 200, description = "Read position of the current user", body = ChatRead),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn mark_chat_read_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<MarkRead>,
+) -> Result<impl IntoResponse, AppError> {
+    let read = state.mark_chat_read(&input, id, user.id as _).await?;
+    Ok(Json(read))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/read",
+    responses(
+        (status = 200, description = "Read receipts of the chat members", body = Vec<ChatRead>),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_chat_reads_handler(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let reads = state.list_chat_reads(id).await?;
+    Ok(Json(reads))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn delete_chat_should_require_permission() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = ParamChat::new("temp", &[2, 3], false);
+        let chat = state.create_chat(&input, 3, 1).await?;
+
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        let member = state.load_member(user).await?;
+        let ret = delete_chat_handler(member, State(state.clone()), Path(chat.id as _))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let member = state.load_member(user).await?;
+        let ret = delete_chat_handler(member, State(state), Path(chat.id as _))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_admins_should_archive_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let archive = || UpdateChatInfo {
+            archived: Some(true),
+            ..Default::default()
+        };
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        let member = state.load_member(user).await?;
+        let ret = update_chat_info_handler(
+            member.clone(),
+            State(state.clone()),
+            Path(2),
+            Json(archive()),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        // members may still change what the chat is about
+        let input = UpdateChatInfo {
+            topic: Some("planning".to_string()),
+            ..Default::default()
+        };
+        let ret = update_chat_info_handler(member, State(state.clone()), Path(2), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let admin = state.load_member(user).await?;
+        let ret = update_chat_info_handler(admin, State(state.clone()), Path(2), Json(archive()))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn members_should_only_remove_lower_roles() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let remove = |members: Vec<i64>| Json(ChatMembers { members });
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        let member = state.load_member(user).await?;
+        // neither the admin nor another member
+        for target in [2, 4] {
+            let ret = remove_chat_members_handler(
+                member.clone(),
+                State(state.clone()),
+                Path(1),
+                remove(vec![target]),
+            )
+            .await
+            .into_response();
+            assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        }
+        assert!(state.is_chat_member(1, 2, 1).await?);
+
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let admin = state.load_member(user).await?;
+        let ret = remove_chat_members_handler(
+            admin.clone(),
+            State(state.clone()),
+            Path(1),
+            remove(vec![1]),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        let ret =
+            remove_chat_members_handler(admin, State(state.clone()), Path(1), remove(vec![2, 4]))
+                .await?
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        assert!(!state.is_chat_member(1, 4, 1).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn guest_should_not_join_channels() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(6).await?.expect("user should exist");
+        let guest = state.load_member(user).await?;
+        let ret = join_channel_handler(guest, State(state.clone()), Path(1))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        assert!(!state.is_chat_member(1, 6, 1).await?);
+
+        let input = ParamChat::new("general2", &[1, 2], true);
+        let chat = state.create_chat(&input, 1, 1).await?;
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        let member = state.load_member(user).await?;
+        let ret = join_channel_handler(member, State(state.clone()), Path(chat.id as _))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        assert!(state.is_chat_member(chat.id as _, 3, 1).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn guest_should_not_add_members_but_may_leave() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(6).await?.expect("user should exist");
+        let member = state.load_member(user.clone()).await?;
+        let input = ChatMembers { members: vec![6] };
+        let ret = add_chat_members_handler(member, State(state.clone()), Path(1), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let user = state.find_user_by_id(5).await?.expect("user should exist");
+        let ret = leave_chat_handler(Extension(user), State(state.clone()), Path(1))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        assert!(!state.is_chat_member(1, 5, 1).await?);
+        Ok(())
+    }
 }
@@ -1,14 +1,18 @@
 use crate::{
-    models::{ChatFile, CreateMessage, DeleteMessage, ListMessage},
-    AppError, AppState,
+    middlewares::CurrentMember,
+    models::{
+        ChatFile, CreateMessage, CreateReaction, DeleteMessage, ListMessage, MessageRevision,
+        UpdateMessage,
+    },
+    AppError, AppState, ErrorOutput,
 };
 use axum::{
     extract::{Multipart, Path, Query, State},
-    http::HeaderMap,
+    http::{HeaderMap, StatusCode},
     response::IntoResponse,
     Extension, Json,
 };
-use chat_core::{Chat, User};
+use chat_core::{Chat, Message, Permission, Reaction, User};
 use tokio::fs::{self};
 use tracing::{info, warn};
 
@@ -39,7 +43,8 @@ pub(crate) async fn send_message_handler(
     delete,
     path = "/api/{id}/message",
     responses(
-        (status = 200, description = "delete message", body = Chat),
+        (status = 200, description = "Tombstone of the deleted message", body = Message),
+        (status = 403, description = "Not the sender nor an admin", body = ErrorOutput),
     ),
     params(
         ("id" = u64, Path, description = "chat id"),
@@ -50,14 +55,86 @@ pub(crate) async fn send_message_handler(
     )
 )]
 pub(crate) async fn delete_message_handler(
+    member: CurrentMember,
     State(state): State<AppState>,
     Path(id): Path<u64>,
     Query(input): Query<DeleteMessage>,
 ) -> Result<impl IntoResponse, AppError> {
-    let message = state.delete_message(input, id).await?;
+    // the role only counts in its own workspace
+    if state
+        .get_chat_by_id(id, member.user.ws_id as _)
+        .await?
+        .is_none()
+    {
+        return Err(AppError::NotFound(format!("chat id {}", id)));
+    }
+    let Some(message) = state
+        .find_message(id, input.message_id)
+        .await?
+        .filter(|m| !m.is_deleted())
+    else {
+        return Err(AppError::NotFound(format!(
+            "message id {}",
+            input.message_id
+        )));
+    };
+    // everyone may delete their own messages
+    if message.sender_id != member.user.id {
+        member.require(Permission::DeleteAnyMessage)?;
+    }
+    let message = state.delete_message(input, id, member.user.id as _).await?;
+    Ok(Json(message))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/chats/{id}/message/{message_id}",
+    responses(
+        (status = 200, description = "Message edited", body = Message),
+        (status = 403, description = "Not the sender", body = ErrorOutput),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+        ("message_id" = u64, Path, description = "message id"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+    Json(input): Json<UpdateMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let message = state
+        .update_message(input, id, message_id, user.id as _)
+        .await?;
     Ok(Json(message))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/message/{message_id}/history",
+    responses(
+        (status = 200, description = "Prior revisions, oldest first", body = Vec<MessageRevision>),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+        ("message_id" = u64, Path, description = "message id"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_message_history_handler(
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let revisions = state.list_message_history(id, message_id).await?;
+    Ok(Json(revisions))
+}
+
 #[utoipa::path(
     get,
     path = "/api/{id}/message",
@@ -81,6 +158,108 @@ pub(crate) async fn list_message_handler(
     Ok(Json(messages))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/message/{message_id}/thread",
+    responses(
+        (status = 200, description = "Replies in the thread", body = Vec<Message>),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+        ("message_id" = u64, Path, description = "message id"),
+        ListMessage,
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_thread_handler(
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+    Query(input): Query<ListMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state.list_thread(input, id, message_id).await?;
+    Ok(Json(messages))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/message/{message_id}/reactions",
+    responses(
+        (status = 200, description = "Reactions on the message, oldest first", body = Vec<Reaction>),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+        ("message_id" = u64, Path, description = "message id"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_reactions_handler(
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let reactions = state.list_reactions(id, message_id).await?;
+    Ok(Json(reactions))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/message/{message_id}/reactions",
+    responses(
+        (status = 201, description = "Reaction added", body = Reaction),
+        (status = 400, description = "Invalid emoji", body = ErrorOutput),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+        ("message_id" = u64, Path, description = "message id"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn add_reaction_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+    Json(input): Json<CreateReaction>,
+) -> Result<impl IntoResponse, AppError> {
+    let reaction = state
+        .add_reaction(&input, id, message_id, user.id as _)
+        .await?;
+    Ok((StatusCode::CREATED, Json(reaction)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/message/{message_id}/reactions/{emoji}",
+    responses(
+        (status = 204, description = "Reaction removed"),
+        (status = 404, description = "Reaction not found", body = ErrorOutput),
+    ),
+    params(
+        ("id" = u64, Path, description = "chat id"),
+        ("message_id" = u64, Path, description = "message id"),
+        ("emoji" = String, Path, description = "emoji to remove, url encoded"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn remove_reaction_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, message_id, emoji)): Path<(u64, u64, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .remove_reaction(id, message_id, user.id as _, &emoji)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 #[utoipa::path(
     get,
     path = "/api/files/{ws_id}/{*path}",
@@ -159,3 +338,46 @@ pub(crate) async fn upload_handler(
 
     Ok(Json(files))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::http::StatusCode;
+    use chat_core::WorkspaceRole;
+
+    #[tokio::test]
+    async fn delete_others_message_should_require_admin() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = DeleteMessage::new(1);
+
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        let member = state.load_member(user).await?;
+        let ret =
+            delete_message_handler(member, State(state.clone()), Path(1), Query(input.clone()))
+                .await
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        // an admin of another workspace
+        let mut user = state.find_user_by_id(2).await?.expect("user should exist");
+        user.ws_id = 2;
+        let member = CurrentMember {
+            user,
+            role: WorkspaceRole::Admin,
+        };
+        let ret =
+            delete_message_handler(member, State(state.clone()), Path(1), Query(input.clone()))
+                .await
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let member = state.load_member(user).await?;
+        let ret = delete_message_handler(member, State(state), Path(1), Query(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        Ok(())
+    }
+}
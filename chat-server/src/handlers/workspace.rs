@@ -1,6 +1,18 @@
-use crate::{AppError, AppState};
-use axum::{extract::State, response::IntoResponse, Extension, Json};
-use chat_core::{ChatUser, User};
+use crate::{
+    middlewares::CurrentMember,
+    models::{
+        AcceptInvite, CreateInvite, InviteOutput, UpdateRole, UpdateWorkspace, UserWorkspace,
+        WorkspaceInvite,
+    },
+    AppError, AppState, ErrorOutput,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{ChatUser, Permission, User, Workspace};
 
 #[utoipa::path(
     get,
@@ -21,3 +33,225 @@ pub(crate) async fn list_chat_user_handler(
 
     Ok(Json(users))
 }
+
+#[utoipa::path(
+    get,
+    path = "/api/workspaces",
+    responses(
+        (status = 200, description = "Workspaces the user belongs to", body = Vec<UserWorkspace>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let workspaces = state.fetch_user_workspaces(user.id as _).await?;
+
+    Ok(Json(workspaces))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/workspaces/join",
+    responses(
+        (status = 200, description = "Joined the workspace", body = Workspace),
+        (status = 400, description = "Invalid invite", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn join_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<AcceptInvite>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.join_workspace(&user, &input.code).await?;
+
+    Ok(Json(ws))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/invites",
+    responses(
+        (status = 200, description = "List of pending invites", body = Vec<WorkspaceInvite>),
+        (status = 403, description = "Permission denied", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_invite_handler(
+    member: CurrentMember,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    member.require(Permission::InviteMembers)?;
+    let invites = state.list_pending_invites(member.user.ws_id as _).await?;
+
+    Ok(Json(invites))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/invites",
+    responses(
+        (status = 201, description = "Invite created", body = InviteOutput),
+        (status = 403, description = "Permission denied", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn create_invite_handler(
+    member: CurrentMember,
+    State(state): State<AppState>,
+    Json(input): Json<CreateInvite>,
+) -> Result<impl IntoResponse, AppError> {
+    member.require(Permission::InviteMembers)?;
+    let invite = state
+        .create_invite(&member.user, member.role, &input)
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/invites/{id}",
+    params(
+        ("id" = u64, Path, description = "Invite id")
+    ),
+    responses(
+        (status = 204, description = "Invite revoked"),
+        (status = 403, description = "Permission denied", body = ErrorOutput),
+        (status = 404, description = "Invite not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn revoke_invite_handler(
+    member: CurrentMember,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    member.require(Permission::InviteMembers)?;
+    state.revoke_invite(member.user.ws_id as _, id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/users/{id}/role",
+    params(
+        ("id" = u64, Path, description = "User id")
+    ),
+    responses(
+        (status = 200, description = "Role updated", body = UpdateRole),
+        (status = 403, description = "Permission denied", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_role_handler(
+    member: CurrentMember,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateRole>,
+) -> Result<impl IntoResponse, AppError> {
+    member.require(Permission::ManageMembers)?;
+    let role = state
+        .update_member_role(member.user.ws_id as _, member.role, id, input.role)
+        .await?;
+
+    Ok(Json(UpdateRole { role }))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/workspace",
+    responses(
+        (status = 200, description = "Workspace updated", body = Workspace),
+        (status = 403, description = "Permission denied", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_workspace_handler(
+    member: CurrentMember,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    member.require(Permission::ManageWorkspace)?;
+    let ws = state
+        .update_workspace(member.user.ws_id as _, &input)
+        .await?;
+
+    Ok(Json(ws))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    async fn member(state: &AppState, user_id: u64) -> Result<CurrentMember> {
+        let user = state
+            .find_user_by_id(user_id)
+            .await?
+            .expect("user should exist");
+        Ok(state.load_member(user).await?)
+    }
+
+    #[tokio::test]
+    async fn only_admins_should_create_invite() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = create_invite_handler(
+            member(&state, 3).await?,
+            State(state.clone()),
+            Json(CreateInvite::default()),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let ret = create_invite_handler(
+            member(&state, 2).await?,
+            State(state),
+            Json(CreateInvite::default()),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_owner_should_update_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateWorkspace {
+            name: "acme2".to_string(),
+        };
+        let ret = update_workspace_handler(
+            member(&state, 2).await?,
+            State(state.clone()),
+            Json(input.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let ret = update_workspace_handler(member(&state, 1).await?, State(state), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        Ok(())
+    }
+}
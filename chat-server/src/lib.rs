@@ -1,6 +1,7 @@
 mod config;
 mod error;
 mod handlers;
+mod mailer;
 mod middlewares;
 mod models;
 mod openapi;
@@ -9,13 +10,16 @@ use anyhow::Context;
 use axum::{
     http::Method,
     middleware::from_fn_with_state,
-    routing::{get, post},
+    routing::{delete, get, patch, post},
     Router,
 };
-use chat_core::{set_layer, verify_token, DecodingKey, EncodingKey, TokenVerify};
+use chat_core::{
+    find_session_user, set_layer, verify_token, DecodingKey, EncodingKey, TokenVerify,
+};
 pub use config::ChatConfig;
 pub use error::{AppError, ErrorOutput};
 use handlers::*;
+pub use mailer::{Mail, Mailer, MemoryMailer};
 use middlewares::verify_chat;
 pub use models::ParamChat;
 use openapi::OpenApiRouter;
@@ -32,8 +36,13 @@ pub struct AppState {
 impl TokenVerify for AppState {
     type Error = AppError;
 
-    fn verify(&self, token: &str) -> Result<chat_core::User, Self::Error> {
-        Ok(self.dk.verify(token)?)
+    async fn verify(&self, token: &str, scope: &str) -> Result<chat_core::User, Self::Error> {
+        let claims = self.dk.verify(token)?;
+        claims.require_scope(scope)?;
+        match find_session_user(&self.pool, &claims).await? {
+            Some(user) => Ok(user),
+            None => Err(AppError::SessionRevoked),
+        }
     }
 }
 
@@ -42,26 +51,53 @@ pub struct AppStateInner {
     pub(crate) config: ChatConfig,
     pub(crate) dk: DecodingKey,
     pub(crate) ek: EncodingKey,
+    pub(crate) mailer: Arc<dyn Mailer>,
     pub(crate) pool: PgPool,
 }
 
 /// Get the router for the chat application
 pub async fn get_router(state: AppState) -> Result<Router, AppError> {
     let chat: Router<AppState> = Router::new()
-        .route(
-            "/{id}",
-            get(get_chat_handler)
-                .patch(update_chat_handler)
-                .delete(delete_chat_handler),
-        )
+        .route("/{id}", get(get_chat_handler).delete(delete_chat_handler))
         .route(
             "/{id}/message",
             get(list_message_handler)
                 .delete(delete_message_handler)
                 .post(send_message_handler),
         )
+        .route("/{id}/info", patch(update_chat_info_handler))
+        .route(
+            "/{id}/members",
+            post(add_chat_members_handler).delete(remove_chat_members_handler),
+        )
+        .route("/{id}/leave", post(leave_chat_handler))
+        .route(
+            "/{id}/read",
+            get(list_chat_reads_handler).post(mark_chat_read_handler),
+        )
+        .route("/{id}/message/{message_id}", patch(update_message_handler))
+        .route(
+            "/{id}/message/{message_id}/history",
+            get(list_message_history_handler),
+        )
+        .route(
+            "/{id}/message/{message_id}/thread",
+            get(list_thread_handler),
+        )
+        .route(
+            "/{id}/message/{message_id}/reactions",
+            get(list_reactions_handler).post(add_reaction_handler),
+        )
+        .route(
+            "/{id}/message/{message_id}/reactions/{emoji}",
+            delete(remove_reaction_handler),
+        )
         .layer(from_fn_with_state(state.clone(), verify_chat))
-        .route("/", get(list_chat_handler).post(create_chat_handler));
+        .route("/", get(list_chat_handler).post(create_chat_handler))
+        // not a member yet
+        .route("/directory", get(list_channels_handler))
+        .route("/direct", post(direct_chat_handler))
+        .route("/{id}/join", post(join_channel_handler));
 
     let cors = cors::CorsLayer::new()
         .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
@@ -70,17 +106,34 @@ pub async fn get_router(state: AppState) -> Result<Router, AppError> {
 
     let api = Router::new()
         .route("/users", get(list_chat_user_handler))
+        .route(
+            "/invites",
+            get(list_invite_handler).post(create_invite_handler),
+        )
+        .route("/invites/{id}", delete(revoke_invite_handler))
+        .route("/users/{id}/role", patch(update_role_handler))
+        .route("/workspaces", get(list_workspace_handler))
+        .route("/workspaces/join", post(join_workspace_handler))
+        .route("/workspace", patch(update_workspace_handler))
         .nest("/chats", chat)
+        .route("/email/verification", post(resend_verification_handler))
         .route("/upload", post(upload_handler))
         .route("/files/{ws_id}/{*path}", get(file_handler))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .route("/signup", post(signup_handler))
         .route("/signin", post(signin_handler))
+        .route("/refresh", post(refresh_handler))
+        .route("/workspaces/{id}/switch", post(switch_workspace_handler))
+        .route("/signout", post(signout_handler))
+        .route("/email/verify", post(verify_email_handler))
+        .route("/password/forgot", post(forgot_password_handler))
+        .route("/password/reset", post(reset_password_handler))
         .layer(cors);
 
     let app = Router::new()
         .openapi()
         .route("/", get(index_handler))
+        .route("/.well-known/jwks.json", get(jwks_handler))
         .nest("/api", api)
         .with_state(state);
 
@@ -100,8 +153,8 @@ impl AppState {
         fs::create_dir_all(&config.server.base_dir)
             .await
             .context("create base_dir failed")?;
-        let dk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
-        let ek = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
+        let (ek, dk) = config.auth.load_keys()?;
+        let mailer = config.mailer.build().context("build mailer failed")?;
         let pool = PgPool::connect(&config.server.db_url)
             .await
             .context("connect to db failed")?;
@@ -110,6 +163,7 @@ impl AppState {
                 config,
                 ek,
                 dk,
+                mailer,
                 pool,
             }),
         })
@@ -127,14 +181,21 @@ impl Debug for AppStateInner {
 #[cfg(feature = "test-util")]
 mod test_util {
     use super::*;
+    use mailer::MemoryMailer;
     use sqlx::Executor;
     use sqlx_db_tester::TestPg;
 
     impl AppState {
         pub async fn new_for_test() -> Result<(TestPg, Self), AppError> {
+            Self::new_for_test_with_mailer(Arc::new(MemoryMailer::default())).await
+        }
+
+        /// Pass in a cloned MemoryMailer to inspect the mails sent
+        pub async fn new_for_test_with_mailer(
+            mailer: Arc<dyn Mailer>,
+        ) -> Result<(TestPg, Self), AppError> {
             let config = ChatConfig::load()?;
-            let dk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
-            let ek = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
+            let (ek, dk) = config.auth.load_keys()?;
             let post = config.server.db_url.rfind('/').expect("invalid db_url");
             let server_url = &config.server.db_url[..post];
             let (tdb, pool) = get_test_pool(Some(server_url)).await;
@@ -143,6 +204,7 @@ mod test_util {
                     config,
                     ek,
                     dk,
+                    mailer,
                     pool,
                 }),
             };
@@ -0,0 +1,54 @@
+use super::{build_message, Mail, MailFuture, Mailer};
+use crate::AppError;
+use lettre::{AsyncFileTransport, AsyncTransport, Tokio1Executor};
+use std::path::Path;
+
+pub struct FileMailer {
+    transport: AsyncFileTransport<Tokio1Executor>,
+    from: String,
+}
+
+impl FileMailer {
+    pub fn try_new(dir: &Path, from: &str) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            transport: AsyncFileTransport::new(dir),
+            from: from.to_string(),
+        })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> MailFuture<'a> {
+        Box::pin(async move {
+            let message = build_message(&self.from, mail)?;
+            self.transport
+                .send(message)
+                .await
+                .map_err(|e| AppError::MailError(e.to_string()))?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn file_mailer_should_write_eml() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("mails-{}", uuid::Uuid::now_v7()));
+        let mailer = FileMailer::try_new(&dir, "Chat <noreply@chat.local>")?;
+        let mail = Mail::new("alice@acme.org", "hello", "hello world");
+        mailer.send(&mail).await?;
+
+        let files: Vec<_> = std::fs::read_dir(&dir)?.collect::<Result<_, _>>()?;
+        assert_eq!(files.len(), 1);
+        let content = std::fs::read_to_string(files[0].path())?;
+        assert!(content.contains("To: alice@acme.org"));
+        assert!(content.contains("hello world"));
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
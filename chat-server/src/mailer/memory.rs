@@ -0,0 +1,26 @@
+use super::{Mail, MailFuture, Mailer};
+use std::sync::{Arc, Mutex};
+
+/// Keeps every mail sent, clones share the same outbox
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMailer {
+    outbox: Arc<Mutex<Vec<Mail>>>,
+}
+
+impl MemoryMailer {
+    pub fn outbox(&self) -> Vec<Mail> {
+        self.outbox.lock().expect("outbox lock poisoned").clone()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> MailFuture<'a> {
+        Box::pin(async move {
+            self.outbox
+                .lock()
+                .expect("outbox lock poisoned")
+                .push(mail.clone());
+            Ok(())
+        })
+    }
+}
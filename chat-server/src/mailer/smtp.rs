@@ -0,0 +1,31 @@
+use super::{build_message, Mail, MailFuture, Mailer};
+use crate::AppError;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn try_new(url: &str, from: &str) -> anyhow::Result<Self> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?.build();
+        Ok(Self {
+            transport,
+            from: from.to_string(),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> MailFuture<'a> {
+        Box::pin(async move {
+            let message = build_message(&self.from, mail)?;
+            self.transport
+                .send(message)
+                .await
+                .map_err(|e| AppError::MailError(e.to_string()))?;
+            Ok(())
+        })
+    }
+}
@@ -5,20 +5,27 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use chat_core::User;
+use std::collections::HashMap;
 
 pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next) -> Response {
     let (mut parts, body) = req.into_parts();
-    let Path(chat_id) = Path::<u64>::from_request_parts(&mut parts, &state)
-        .await
-        .unwrap();
+    // nested routes carry more ids, e.g. /{id}/message/{message_id}
+    let chat_id =
+        match Path::<HashMap<String, String>>::from_request_parts(&mut parts, &state).await {
+            Ok(Path(params)) => params
+                .get("id")
+                .and_then(|id| id.parse().ok())
+                .unwrap_or_default(),
+            Err(e) => return e.into_response(),
+        };
     let user = parts.extensions.get::<User>().unwrap();
-    // verify if user_id is a member of chat_id
+    // verify if user_id is a member of chat_id in the token's active workspace
     if !state
-        .is_chat_member(chat_id, user.id as _)
+        .is_chat_member(chat_id, user.id as _, user.ws_id as _)
         .await
         .unwrap_or_default()
     {
-        let err = AppError::CreateMessageError(format!(
+        let err = AppError::PermissionDenied(format!(
             "User {} is not a member of chat {}",
             user.id, chat_id
         ));
@@ -36,20 +43,21 @@ mod tests {
     use axum::{
         body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get, Router,
     };
-    use chat_core::verify_token;
+    use chat_core::{verify_token, WorkspaceRole};
     use tower::ServiceExt;
 
     #[tokio::test]
     async fn verify_chat_middleware_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let user = state.find_user_by_id(1).await?.expect("user not exists");
-        let token = state.ek.sign(user)?;
+        let (session, _) = state.create_session(user.id as _, user.ws_id as _).await?;
+        let token = state.ek.sign(user, session.id)?;
 
         let app = Router::new()
             .route("/chat/{id}/message", get(handler))
             .layer(from_fn_with_state(state.clone(), verify_chat))
             .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
-            .with_state(state);
+            .with_state(state.clone());
 
         // user in chat
         let req = Request::builder()
@@ -65,7 +73,23 @@ mod tests {
             .header("Authorization", format!("Bearer {token}"))
             .body(Body::empty())?;
         let res = app.clone().oneshot(req).await?;
-        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // chats of the other workspaces are out of reach after a switch
+        let mut conn = state.pool.acquire().await?;
+        state
+            .add_workspace_member(&mut conn, 2, 1, WorkspaceRole::Member)
+            .await?;
+        let (session, _) = state.create_session(1, 2).await?;
+        let mut user = state.find_user_by_id(1).await?.expect("user not exists");
+        user.ws_id = 2;
+        let token = state.ek.sign(user, session.id)?;
+        let req = Request::builder()
+            .uri("/chat/1/message")
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
 
         Ok(())
     }
@@ -0,0 +1,97 @@
+use crate::{AppError, AppState};
+use axum::{extract::FromRequestParts, http::request::Parts};
+use chat_core::{Permission, User, WorkspaceRole};
+
+/// The signed in user together with their role in the current workspace
+#[derive(Debug, Clone)]
+pub struct CurrentMember {
+    pub user: User,
+    pub role: WorkspaceRole,
+}
+
+impl CurrentMember {
+    pub fn can(&self, permission: Permission) -> bool {
+        self.role.can(permission)
+    }
+
+    pub fn require(&self, permission: Permission) -> Result<(), AppError> {
+        if self.can(permission) {
+            Ok(())
+        } else {
+            Err(AppError::PermissionDenied(format!(
+                "{:?} of workspace {} can't {:?}",
+                self.role, self.user.ws_id, permission
+            )))
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for CurrentMember {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        // set by verify_token
+        let Some(user) = parts.extensions.get::<User>().cloned() else {
+            return Err(AppError::PermissionDenied("user not signed in".to_string()));
+        };
+        state.load_member(user).await
+    }
+}
+
+impl AppState {
+    pub async fn load_member(&self, user: User) -> Result<CurrentMember, AppError> {
+        match self.get_member_role(user.ws_id as _, user.id as _).await? {
+            Some(role) => Ok(CurrentMember { user, role }),
+            None => Err(AppError::PermissionDenied(format!(
+                "user {} is not a member of workspace {}",
+                user.id, user.ws_id
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{
+        body::Body, extract::Request, http::StatusCode, middleware::from_fn_with_state,
+        routing::delete, Router,
+    };
+    use chat_core::verify_token;
+    use tower::ServiceExt;
+
+    async fn handler(member: CurrentMember) -> Result<StatusCode, AppError> {
+        member.require(Permission::DeleteChat)?;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[tokio::test]
+    async fn current_member_should_enforce_permission() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let app = Router::new()
+            .route("/chats/{id}", delete(handler))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state.clone());
+
+        for (user_id, status) in [(2, StatusCode::NO_CONTENT), (3, StatusCode::FORBIDDEN)] {
+            let user = state
+                .find_user_by_id(user_id)
+                .await?
+                .expect("user should exist");
+            let (session, _) = state.create_session(user.id as _, user.ws_id as _).await?;
+            let token = state.ek.sign(user, session.id)?;
+            let req = Request::builder()
+                .method("DELETE")
+                .uri("/chats/1")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())?;
+            let res = app.clone().oneshot(req).await?;
+            assert_eq!(res.status(), status);
+        }
+        Ok(())
+    }
+}
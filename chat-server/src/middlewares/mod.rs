@@ -1,3 +1,5 @@
 mod chat;
+mod member;
 
 pub use chat::verify_chat;
+pub use member::CurrentMember;
@@ -0,0 +1,139 @@
+use crate::{AppError, AppState};
+use chat_core::Chat;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, FromRow};
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct ListChannels {
+    /// matches part of the name or description, case insensitive
+    #[serde(default)]
+    pub q: Option<String>,
+    #[serde(default)]
+    pub offset: u64,
+    #[serde(default)]
+    pub limit: u64,
+}
+
+/// A public channel as listed in the directory
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
+pub struct ChannelSummary {
+    pub id: i64,
+    pub name: String,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    pub icon: Option<String>,
+    pub member_count: i32,
+    /// whether the current user is in it already
+    pub joined: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AppState {
+    /// Public channels of the workspace which aren't archived, whether the user is in them or not
+    pub async fn list_channels(
+        &self,
+        input: &ListChannels,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<Vec<ChannelSummary>, AppError> {
+        let limit = match input.limit {
+            0 => 20,
+            1..=100 => input.limit as i64,
+            _ => 100,
+        };
+        let q = input.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+
+        let channels = query_as(
+            "SELECT id, name, topic, description, icon, cardinality(members) AS member_count, $2 = ANY(members) AS joined, created_at FROM chats WHERE ws_id = $1 AND type = 'public_channel' AND archived_at IS NULL AND ($3::text IS NULL OR strpos(lower(name), lower($3)) > 0 OR strpos(lower(COALESCE(description, '')), lower($3)) > 0) ORDER BY name LIMIT $4 OFFSET $5",
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .bind(q)
+        .bind(limit)
+        .bind(input.offset as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(channels)
+    }
+
+    /// Add the user to a public channel, joining twice is fine
+    pub async fn join_channel(&self, id: u64, ws_id: u64, user_id: u64) -> Result<Chat, AppError> {
+        let chat: Option<Chat> = query_as(
+            "UPDATE chats SET members = members || $3::bigint WHERE id = $1 AND ws_id = $2 AND type = 'public_channel' AND archived_at IS NULL AND $3 <> ALL(members) RETURNING id, ws_id, name, type, members, topic, description, icon, archived_at, created_at",
+        )
+        .bind(id as i64)
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(chat) = chat {
+            return Ok(chat);
+        }
+
+        // private chats are not supposed to be found this way
+        match self.get_chat_by_id(id, ws_id).await? {
+            Some(chat) if chat.members.contains(&(user_id as i64)) => Ok(chat),
+            _ => Err(AppError::NotFound(format!("channel id {}", id))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ParamChat, UpdateChatInfo};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn list_channels_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = ParamChat::new("random", &[2, 3], true);
+        state.create_chat(&input, 2, 1).await?;
+
+        let channels = state.list_channels(&ListChannels::default(), 1, 6).await?;
+        let names: Vec<_> = channels.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["general", "random"]);
+        assert_eq!(channels[0].member_count, 5);
+        assert!(!channels[0].joined);
+
+        let input = ListChannels {
+            q: Some("RAND".to_string()),
+            ..Default::default()
+        };
+        let channels = state.list_channels(&input, 1, 2).await?;
+        assert_eq!(channels.len(), 1);
+        assert!(channels[0].joined);
+
+        // archived channels are out of the directory and can't be joined
+        let input = UpdateChatInfo {
+            archived: Some(true),
+            ..Default::default()
+        };
+        state.update_chat_info(1, 1, &input).await?;
+        let channels = state.list_channels(&ListChannels::default(), 1, 6).await?;
+        assert_eq!(channels.len(), 1);
+        let ret = state.join_channel(1, 1, 6).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn join_channel_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let chat = state.join_channel(1, 1, 6).await?;
+        assert_eq!(chat.members, vec![1, 2, 3, 4, 5, 6]);
+        // joining again changes nothing
+        let chat = state.join_channel(1, 1, 6).await?;
+        assert_eq!(chat.members.len(), 6);
+
+        // private chats can't be joined
+        let ret = state.join_channel(2, 1, 6).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        let ret = state.join_channel(1, 2, 6).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+}
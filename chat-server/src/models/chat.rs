@@ -1,8 +1,10 @@
+use super::ChatFile;
 use crate::{AppError, AppState};
 use chat_core::{Chat, ChatType};
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as};
-use utoipa::ToSchema;
+use sqlx::{query, query_as, query_scalar, Postgres, Transaction};
+use std::str::FromStr;
+use utoipa::{IntoParams, ToSchema};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default, ToSchema)]
 pub struct ParamChat {
@@ -12,6 +14,45 @@ pub struct ParamChat {
     pub public: bool,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct ListChats {
+    /// archived chats are left out unless asked for
+    #[serde(default)]
+    pub include_archived: bool,
+}
+
+/// Fields to change, the ones left out stay as they are and an empty string clears one
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct UpdateChatInfo {
+    /// only channels have a name, it can't be cleared
+    #[serde(default)]
+    pub name: Option<String>,
+    /// `""` clears the topic
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// `""` clears the description
+    #[serde(default)]
+    pub description: Option<String>,
+    /// an uploaded file of the workspace, `""` clears the icon
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// only admins may archive or unarchive
+    #[serde(default)]
+    pub archived: Option<bool>,
+}
+
+/// The other person of a single chat
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct CreateDirectChat {
+    pub user_id: i64,
+}
+
+/// Users to add to or remove from a chat
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default, ToSchema)]
+pub struct ChatMembers {
+    pub members: Vec<i64>,
+}
+
 impl AppState {
     #[allow(dead_code)]
     pub async fn create_chat(
@@ -21,11 +62,23 @@ impl AppState {
         ws_id: u64,
     ) -> Result<Chat, AppError> {
         let chat_type = self
-            .number_of_people_and_get_chat_type(input, user_id)
+            .number_of_people_and_get_chat_type(input, user_id, ws_id)
             .await?;
+        if chat_type == ChatType::Single {
+            let peer_id = input
+                .members
+                .iter()
+                .find(|id| **id != user_id as i64)
+                .copied()
+                .unwrap_or(user_id as i64);
+            let (chat, _) = self
+                .get_or_create_direct_chat(ws_id, user_id, peer_id)
+                .await?;
+            return Ok(chat);
+        }
 
         let chat = query_as(
-            "INSERT INTO chats (ws_id, name, type, members) VALUES ($1, $2, $3, $4) RETURNING id, ws_id, name, type, members, created_at",
+            "INSERT INTO chats (ws_id, name, type, members) VALUES ($1, $2, $3, $4) RETURNING id, ws_id, name, type, members, topic, description, icon, archived_at, created_at",
         )
         .bind(ws_id as i64)
         .bind(&input.name)
@@ -37,39 +90,252 @@ impl AppState {
         Ok(chat)
     }
 
-    pub async fn update_chat(
+    pub async fn delete_chat(&self, id: u64, ws_id: u64) -> Result<Chat, AppError> {
+        let chat: Option<Chat> = query_as(
+            "DELETE FROM chats WHERE id = $1 AND ws_id = $2 RETURNING id, ws_id, name, type, members, topic, description, icon, archived_at, created_at",
+        )
+        .bind(id as i64)
+        .bind(ws_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        chat.ok_or_else(|| AppError::NotFound(format!("chat id {}", id)))
+    }
+
+    /// The single chat of the two users, created if there is none yet,
+    /// returns whether it was created
+    pub async fn get_or_create_direct_chat(
         &self,
-        id: u64,
         ws_id: u64,
-        input: &ParamChat,
         user_id: u64,
+        peer_id: i64,
+    ) -> Result<(Chat, bool), AppError> {
+        let user_id = user_id as i64;
+        if peer_id == user_id {
+            return Err(AppError::CreateChatError(
+                "Can't start a direct chat with yourself".to_string(),
+            ));
+        }
+        let exists: bool = query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM workspace_members WHERE ws_id = $1 AND user_id = $2)",
+        )
+        .bind(ws_id as i64)
+        .bind(peer_id)
+        .fetch_one(&self.pool)
+        .await?;
+        if !exists {
+            return Err(AppError::CreateChatError(format!(
+                "User {} does not belong to the workspace",
+                peer_id
+            )));
+        }
+
+        // stored in order, the unique index covers either order anyway
+        let members = [user_id.min(peer_id), user_id.max(peer_id)];
+        let chat: Option<Chat> = query_as(
+            "INSERT INTO chats (ws_id, type, members) VALUES ($1, 'single', $2) ON CONFLICT (ws_id, LEAST(members[1], members[2]), GREATEST(members[1], members[2])) WHERE type = 'single' DO NOTHING RETURNING id, ws_id, name, type, members, topic, description, icon, archived_at, created_at",
+        )
+        .bind(ws_id as i64)
+        .bind(members)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(chat) = chat {
+            return Ok((chat, true));
+        }
+
+        let chat = query_as(
+            "SELECT id, ws_id, name, type, members, topic, description, icon, archived_at, created_at FROM chats WHERE ws_id = $1 AND type = 'single' AND LEAST(members[1], members[2]) = $2 AND GREATEST(members[1], members[2]) = $3",
+        )
+        .bind(ws_id as i64)
+        .bind(members[0])
+        .bind(members[1])
+        .fetch_one(&self.pool)
+        .await?;
+        Ok((chat, false))
+    }
+
+    /// Add workspace members to the chat, members already in it are skipped
+    pub async fn add_chat_members(
+        &self,
+        id: u64,
+        ws_id: u64,
+        input: &ChatMembers,
     ) -> Result<Chat, AppError> {
-        if self.get_chat_by_id(id, ws_id).await?.is_none() {
-            return Err(AppError::NotFound("chat not found".to_string()));
+        let mut tx = self.pool.begin().await?;
+        // locked so concurrent changes apply one after the other
+        let chat = lock_chat(&mut tx, id, ws_id).await?;
+        if chat.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Single chat can't change members".to_string(),
+            ));
         }
 
-        let chat_type = self
-            .number_of_people_and_get_chat_type(input, user_id)
-            .await?;
+        let mut added: Vec<i64> = input
+            .members
+            .iter()
+            .filter(|id| !chat.members.contains(id))
+            .copied()
+            .collect();
+        added.sort();
+        added.dedup();
+        if added.is_empty() {
+            return Ok(chat);
+        }
+        if chat.name.is_none() && chat.members.len() + added.len() > 8 {
+            return Err(AppError::UpdateChatError(
+                "Group chat with more than 8 members must have a name".to_string(),
+            ));
+        }
+        let count: i64 = query_scalar(
+            "SELECT COUNT(*) FROM workspace_members WHERE ws_id = $1 AND user_id = ANY($2)",
+        )
+        .bind(ws_id as i64)
+        .bind(&added)
+        .fetch_one(&mut *tx)
+        .await?;
+        if count as usize != added.len() {
+            return Err(AppError::UpdateChatError(
+                "Some members do not belong to the workspace".to_string(),
+            ));
+        }
 
-        let chat: Chat = query_as(
-            "UPDATE chats SET name = $1, type = $2, members = $3 WHERE id = $4 RETURNING id, ws_id, name, type, members, created_at",
+        let chat = query_as(
+            "UPDATE chats SET members = members || $2 WHERE id = $1 RETURNING id, ws_id, name, type, members, topic, description, icon, archived_at, created_at",
         )
-        .bind(&input.name)
-        .bind(chat_type)
-        .bind(&input.members)
         .bind(id as i64)
-        .fetch_one(&self.pool)
+        .bind(&added)
+        .fetch_one(&mut *tx)
         .await?;
+        tx.commit().await?;
 
         Ok(chat)
     }
 
-    pub async fn delete_chat(&self, id: u64) -> Result<Chat, AppError> {
-        let chat: Chat = query_as(
-            "DELETE FROM chats WHERE id = $1 RETURNING id, ws_id, name, type, members, created_at",
+    /// Remove users from the chat, users who aren't in it are skipped
+    pub async fn remove_chat_members(
+        &self,
+        id: u64,
+        ws_id: u64,
+        input: &ChatMembers,
+    ) -> Result<Chat, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let chat = lock_chat(&mut tx, id, ws_id).await?;
+        if chat.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Single chat can't change members".to_string(),
+            ));
+        }
+
+        let removed: Vec<i64> = chat
+            .members
+            .iter()
+            .filter(|id| input.members.contains(id))
+            .copied()
+            .collect();
+        if removed.is_empty() {
+            return Ok(chat);
+        }
+        if chat.members.len() - removed.len() < 2 {
+            return Err(AppError::UpdateChatError(
+                "Chat must have at least 2 members".to_string(),
+            ));
+        }
+
+        let chat = query_as(
+            "UPDATE chats SET members = ARRAY(SELECT m FROM unnest(members) AS m WHERE m <> ALL($2)) WHERE id = $1 RETURNING id, ws_id, name, type, members, topic, description, icon, archived_at, created_at",
+        )
+        .bind(id as i64)
+        .bind(&removed)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(chat)
+    }
+
+    /// Take the user out of the chat, which may shrink below the minimum unlike
+    /// removing members, the last one out deletes the chat
+    pub async fn leave_chat(&self, id: u64, ws_id: u64, user_id: u64) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let chat = lock_chat(&mut tx, id, ws_id).await?;
+        if chat.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Single chat can't change members".to_string(),
+            ));
+        }
+        if !chat.members.contains(&(user_id as i64)) {
+            return Ok(());
+        }
+
+        if chat.members.len() == 1 {
+            query("DELETE FROM chats WHERE id = $1")
+                .bind(id as i64)
+                .execute(&mut *tx)
+                .await?;
+        } else {
+            query("UPDATE chats SET members = array_remove(members, $2) WHERE id = $1")
+                .bind(id as i64)
+                .bind(user_id as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Change what the chat is about, archiving makes it read-only
+    pub async fn update_chat_info(
+        &self,
+        id: u64,
+        ws_id: u64,
+        input: &UpdateChatInfo,
+    ) -> Result<Chat, AppError> {
+        let Some(chat) = self.get_chat_by_id(id, ws_id).await? else {
+            return Err(AppError::NotFound(format!("chat id {}", id)));
+        };
+        if let Some(name) = &input.name {
+            if !matches!(
+                chat.r#type,
+                ChatType::PublicChannel | ChatType::PrivateChannel
+            ) {
+                return Err(AppError::UpdateChatError(
+                    "Only channels can be renamed".to_string(),
+                ));
+            }
+            if name.chars().count() < 3 {
+                return Err(AppError::UpdateChatError(
+                    "Chat name must have at least 3 characters".to_string(),
+                ));
+            }
+        }
+        for (field, value) in [("Topic", &input.topic), ("Description", &input.description)] {
+            if value.as_ref().is_some_and(|v| v.chars().count() > 256) {
+                return Err(AppError::UpdateChatError(format!(
+                    "{} must have at most 256 characters",
+                    field
+                )));
+            }
+        }
+        if let Some(icon) = input.icon.as_deref().filter(|icon| !icon.is_empty()) {
+            let file = ChatFile::from_str(icon)?;
+            if file.ws_id != ws_id || !file.path(&self.config.server.base_dir).exists() {
+                return Err(AppError::UpdateChatError(format!(
+                    "File {} doesn't exist",
+                    icon
+                )));
+            }
+        }
+
+        let chat = query_as(
+            "UPDATE chats SET name = COALESCE($2, name), topic = CASE WHEN $3::text IS NULL THEN topic ELSE NULLIF($3, '') END, description = CASE WHEN $4::text IS NULL THEN description ELSE NULLIF($4, '') END, icon = CASE WHEN $5::text IS NULL THEN icon ELSE NULLIF($5, '') END, archived_at = CASE WHEN $6::bool IS NULL THEN archived_at WHEN $6 THEN COALESCE(archived_at, NOW()) ELSE NULL END WHERE id = $1 RETURNING id, ws_id, name, type, members, topic, description, icon, archived_at, created_at",
         )
         .bind(id as i64)
+        .bind(&input.name)
+        .bind(&input.topic)
+        .bind(&input.description)
+        .bind(&input.icon)
+        .bind(input.archived)
         .fetch_one(&self.pool)
         .await?;
 
@@ -77,12 +343,18 @@ impl AppState {
     }
 
     #[allow(dead_code)]
-    pub async fn fetch_chat_all(&self, ws_id: u64, user_id: u64) -> Result<Vec<Chat>, AppError> {
+    pub async fn fetch_chat_all(
+        &self,
+        input: &ListChats,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<Vec<Chat>, AppError> {
         let chats = query_as(
-            "SELECT id, ws_id, name, type, members, created_at FROM chats WHERE ws_id = $1 AND $2 = ANY(members)",
+            "SELECT c.id, c.ws_id, c.name, c.type, c.members, c.topic, c.description, c.icon, c.archived_at, c.created_at, COALESCE(r.last_read_id, 0) AS last_read_id, (SELECT COUNT(*) FROM messages m WHERE m.chat_id = c.id AND m.id > COALESCE(r.last_read_id, 0) AND m.sender_id <> $2 AND m.deleted_at IS NULL AND m.parent_id IS NULL) AS unread_count, (SELECT COUNT(DISTINCT m.id) FROM messages m JOIN message_mentions mm ON mm.message_id = m.id WHERE m.chat_id = c.id AND m.id > COALESCE(r.last_read_id, 0) AND m.sender_id <> $2 AND m.deleted_at IS NULL AND m.parent_id IS NULL AND (mm.user_id = $2 OR mm.kind <> 'user')) AS mention_count FROM chats c LEFT JOIN chat_reads r ON r.chat_id = c.id AND r.user_id = $2 WHERE c.ws_id = $1 AND $2 = ANY(c.members) AND ($3 OR c.archived_at IS NULL)",
         )
         .bind(ws_id as i64)
         .bind(user_id as i64)
+        .bind(input.include_archived)
         .fetch_all(&self.pool)
         .await?;
 
@@ -91,7 +363,7 @@ impl AppState {
 
     pub async fn get_chat_by_id(&self, chat_id: u64, ws_id: u64) -> Result<Option<Chat>, AppError> {
         let chat = query_as(
-            "SELECT id, ws_id, name, type, members, created_at FROM chats WHERE id = $1 AND ws_id = $2",
+            "SELECT id, ws_id, name, type, members, topic, description, icon, archived_at, created_at FROM chats WHERE id = $1 AND ws_id = $2",
         )
         .bind(chat_id as i64)
         .bind(ws_id as i64)
@@ -105,6 +377,7 @@ impl AppState {
         &self,
         input: &ParamChat,
         user_id: u64,
+        ws_id: u64,
     ) -> Result<ChatType, AppError> {
         let len = input.members.len();
         if len < 2 {
@@ -121,7 +394,7 @@ impl AppState {
         }
 
         if let Some(name) = &input.name {
-            if name.len() < 3 {
+            if name.chars().count() < 3 {
                 return Err(AppError::CreateChatError(
                     "Chat name must have at least 3 characters".to_string(),
                 ));
@@ -133,10 +406,10 @@ impl AppState {
                 "Group chat with more than 8 members must have a name".to_string(),
             ));
         }
-        let users = self.fetch_chat_user_by_ids(&input.members).await?;
+        let users = self.fetch_chat_user_by_ids(ws_id, &input.members).await?;
         if users.len() != len {
-            return Err(AppError::UpdateChatError(
-                "Some members do not exist".to_string(),
+            return Err(AppError::CreateChatError(
+                "Some members do not belong to the workspace".to_string(),
             ));
         }
         let chat_type = match (&input.name, len) {
@@ -153,10 +426,17 @@ impl AppState {
         Ok(chat_type)
     }
 
-    pub async fn is_chat_member(&self, chat_id: u64, user_id: u64) -> Result<bool, AppError> {
-        let chat = query("SELECT 1 FROM chats WHERE id = $1 AND $2 = ANY(members)")
+    /// Membership in a chat of the given workspace, chats of the user's other workspaces don't count
+    pub async fn is_chat_member(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<bool, AppError> {
+        let chat = query("SELECT 1 FROM chats WHERE id = $1 AND $2 = ANY(members) AND ws_id = $3")
             .bind(chat_id as i64)
             .bind(user_id as i64)
+            .bind(ws_id as i64)
             .fetch_optional(&self.pool)
             .await?;
 
@@ -164,6 +444,30 @@ impl AppState {
     }
 }
 
+async fn lock_chat(
+    tx: &mut Transaction<'_, Postgres>,
+    id: u64,
+    ws_id: u64,
+) -> Result<Chat, AppError> {
+    let chat: Option<Chat> = query_as(
+        "SELECT id, ws_id, name, type, members, topic, description, icon, archived_at, created_at FROM chats WHERE id = $1 AND ws_id = $2 FOR UPDATE",
+    )
+    .bind(id as i64)
+    .bind(ws_id as i64)
+    .fetch_optional(&mut **tx)
+    .await?;
+    chat.ok_or_else(|| AppError::NotFound(format!("chat id {}", id)))
+}
+
+#[cfg(test)]
+impl ChatMembers {
+    pub fn new(members: &[i64]) -> Self {
+        Self {
+            members: members.to_vec(),
+        }
+    }
+}
+
 #[cfg(test)]
 impl ParamChat {
     pub fn new(name: &str, members: &[i64], public: bool) -> Self {
@@ -183,6 +487,7 @@ impl ParamChat {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::CreateUser;
     use anyhow::Result;
 
     #[tokio::test]
@@ -197,6 +502,29 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn direct_chat_should_be_created_once() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 3 is between 1 and 2 already
+        let (chat, created) = state.get_or_create_direct_chat(1, 2, 1).await?;
+        assert_eq!((chat.id, created), (3, false));
+        let (chat, created) = state.get_or_create_direct_chat(1, 1, 5).await?;
+        assert!(created);
+        assert_eq!(chat.members, vec![1, 5]);
+        let (again, created) = state.get_or_create_direct_chat(1, 5, 1).await?;
+        assert_eq!((again.id, created), (chat.id, false));
+
+        // the generic endpoint hands out the same chat
+        let input = ParamChat::new("", &[5, 1], false);
+        assert_eq!(state.create_chat(&input, 5, 1).await?.id, chat.id);
+
+        let ret = state.get_or_create_direct_chat(1, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+        let ret = state.get_or_create_direct_chat(1, 1, 99).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn create_public_named_chat_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -209,11 +537,48 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn chat_names_should_be_counted_in_characters() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // two characters but six bytes
+        let input = ParamChat::new("研发", &[2, 3, 4], true);
+        let ret = state.create_chat(&input, 2, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+        let input = UpdateChatInfo {
+            name: Some("研发".to_string()),
+            ..Default::default()
+        };
+        let ret = state.update_chat_info(1, 1, &input).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+
+        let input = ParamChat::new("研发部", &[2, 3, 4], true);
+        let chat = state.create_chat(&input, 2, 1).await?;
+        assert_eq!(chat.name.as_deref(), Some("研发部"));
+        let input = UpdateChatInfo {
+            name: Some("测试组".to_string()),
+            ..Default::default()
+        };
+        let chat = state.update_chat_info(chat.id as _, 1, &input).await?;
+        assert_eq!(chat.name.as_deref(), Some("测试组"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_chat_should_reject_users_of_other_workspaces() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("other", "eve", "eve@other.org", "123456");
+        let user = state.create_user(&input).await?;
+        let input = ParamChat::new("strangers", &[1, 2, user.id], false);
+        let ret = state.create_chat(&input, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn chat_get_by_id() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let chats: Vec<Chat> =
-            query_as("SELECT id, ws_id, name, type, members, created_at FROM chats")
+            query_as("SELECT id, ws_id, name, type, members, topic, description, icon, archived_at, created_at FROM chats")
                 .fetch_all(&state.pool)
                 .await?;
         println!("{chats:?}");
@@ -229,19 +594,59 @@ mod tests {
     #[tokio::test]
     async fn chat_fetch_all_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let chats = state.fetch_chat_all(1, 1).await?;
+        let chats = state.fetch_chat_all(&ListChats::default(), 1, 1).await?;
         assert_eq!(chats.len(), 4);
         Ok(())
     }
 
     #[tokio::test]
-    async fn update_chat_should_work() -> Result<()> {
+    async fn update_chat_info_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let mut input = ParamChat::new("general1", &[2, 3, 4], true);
-        let chat = state.create_chat(&input, 2, 1).await?;
-        input.name = Some("test".to_string());
-        let chat = state.update_chat(chat.id as _, 1, &input, 2).await?;
-        assert_eq!(chat.name, Some("test".to_string()));
+        let input = UpdateChatInfo {
+            topic: Some("release on friday".to_string()),
+            description: Some("everything".to_string()),
+            ..Default::default()
+        };
+        let chat = state.update_chat_info(1, 1, &input).await?;
+        assert_eq!(chat.topic.as_deref(), Some("release on friday"));
+        assert_eq!(chat.name.as_deref(), Some("general"));
+
+        let input = UpdateChatInfo {
+            topic: Some("".to_string()),
+            archived: Some(true),
+            ..Default::default()
+        };
+        let chat = state.update_chat_info(1, 1, &input).await?;
+        assert!(chat.topic.is_none());
+        assert_eq!(chat.description.as_deref(), Some("everything"));
+        assert!(chat.archived_at.is_some());
+
+        let chats = state.fetch_chat_all(&ListChats::default(), 1, 1).await?;
+        assert_eq!(chats.len(), 3);
+        let input = ListChats {
+            include_archived: true,
+        };
+        assert_eq!(state.fetch_chat_all(&input, 1, 1).await?.len(), 4);
+
+        // only channels have names, icons have to be uploaded first
+        let input = UpdateChatInfo {
+            name: Some("buddies".to_string()),
+            ..Default::default()
+        };
+        let ret = state.update_chat_info(4, 1, &input).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        let input = UpdateChatInfo {
+            description: Some("".to_string()),
+            ..Default::default()
+        };
+        let chat = state.update_chat_info(1, 1, &input).await?;
+        assert!(chat.description.is_none());
+        let input = UpdateChatInfo {
+            icon: Some("/files/1/abc/def/0123.png".to_string()),
+            ..Default::default()
+        };
+        let ret = state.update_chat_info(1, 1, &input).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
         Ok(())
     }
 
@@ -250,7 +655,11 @@ mod tests {
         let (_tdb, state) = AppState::new_for_test().await?;
         let input = ParamChat::new("general1", &[2, 3, 4], true);
         let chat = state.create_chat(&input, 2, 1).await?;
-        let chat = state.delete_chat(chat.id as _).await?;
+        // chats of other workspaces are out of reach
+        let ret = state.delete_chat(chat.id as _, 2).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let chat = state.delete_chat(chat.id as _, 1).await?;
 
         if state
             .get_chat_by_id(chat.id as _, chat.ws_id as _)
@@ -263,19 +672,102 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn add_chat_members_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let chat = state
+            .add_chat_members(4, 1, &ChatMembers::new(&[3, 5, 6, 5]))
+            .await?;
+        assert_eq!(chat.members, vec![1, 3, 4, 5, 6]);
+        assert_eq!(chat.r#type, ChatType::Group);
+
+        // single chats stay between two people
+        let ret = state.add_chat_members(3, 1, &ChatMembers::new(&[3])).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        // only people of the workspace can be added
+        let ret = state.add_chat_members(2, 1, &ChatMembers::new(&[99])).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        // unnamed groups are limited
+        let ret = state
+            .add_chat_members(4, 1, &ChatMembers::new(&[2, 5, 6, 7, 8, 9]))
+            .await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        let ret = state.add_chat_members(4, 2, &ChatMembers::new(&[2])).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_chat_members_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let chat = state
+            .remove_chat_members(1, 1, &ChatMembers::new(&[2, 4, 6]))
+            .await?;
+        assert_eq!(chat.members, vec![1, 3, 5]);
+        assert!(!state.is_chat_member(1, 2, 1).await?);
+
+        let ret = state
+            .remove_chat_members(3, 1, &ChatMembers::new(&[2]))
+            .await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        let ret = state
+            .remove_chat_members(2, 1, &ChatMembers::new(&[1, 2]))
+            .await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leave_chat_should_delete_it_once_empty() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = ParamChat::new("", &[1, 2, 3], false);
+        let chat = state.create_chat(&input, 1, 1).await?;
+        assert_eq!(chat.r#type, ChatType::Group);
+
+        // leaving isn't held to the minimum number of members
+        state.leave_chat(chat.id as _, 1, 2).await?;
+        state.leave_chat(chat.id as _, 1, 3).await?;
+        let ret = state.get_chat_by_id(chat.id as _, 1).await?.unwrap();
+        assert_eq!(ret.members, vec![1]);
+        state.leave_chat(chat.id as _, 1, 1).await?;
+        assert!(state.get_chat_by_id(chat.id as _, 1).await?.is_none());
+
+        let ret = state.leave_chat(3, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_member_changes_should_not_overwrite_each_other() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (four, five) = (ChatMembers::new(&[4]), ChatMembers::new(&[5]));
+        let (a, b) = tokio::join!(
+            state.add_chat_members(2, 1, &four),
+            state.add_chat_members(2, 1, &five),
+        );
+        a?;
+        b?;
+        let chat = state
+            .get_chat_by_id(2, 1)
+            .await?
+            .expect("chat should exist");
+        assert_eq!(chat.members.len(), 5);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn chat_is_member_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let is_member = state.is_chat_member(1, 5).await?;
+        let is_member = state.is_chat_member(1, 5, 1).await?;
         assert!(is_member);
 
-        let is_member = state.is_chat_member(2, 5).await?;
+        let is_member = state.is_chat_member(2, 5, 1).await?;
         assert!(!is_member);
 
-        let is_member = state.is_chat_member(3, 2).await?;
+        let is_member = state.is_chat_member(3, 2, 1).await?;
         assert!(is_member);
 
-        let is_member = state.is_chat_member(4, 2).await?;
+        let is_member = state.is_chat_member(4, 2, 1).await?;
         assert!(!is_member);
 
         Ok(())
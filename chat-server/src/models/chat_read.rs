@@ -0,0 +1,118 @@
+use crate::{AppError, AppState};
+use chat_core::ChatRead;
+use serde::{Deserialize, Serialize};
+use sqlx::query_as;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MarkRead {
+    /// everything up to and including this message is read
+    pub message_id: u64,
+}
+
+impl AppState {
+    /// Move the read position of a member forward, it never goes back
+    pub async fn mark_chat_read(
+        &self,
+        input: &MarkRead,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<ChatRead, AppError> {
+        if self
+            .find_message(chat_id, input.message_id)
+            .await?
+            .is_none()
+        {
+            return Err(AppError::NotFound(format!(
+                "message id {}",
+                input.message_id
+            )));
+        }
+
+        let read = query_as(
+            "INSERT INTO chat_reads (chat_id, user_id, last_read_id) VALUES ($1, $2, $3) ON CONFLICT (chat_id, user_id) DO UPDATE SET last_read_id = GREATEST(chat_reads.last_read_id, EXCLUDED.last_read_id), updated_at = NOW() RETURNING chat_id, user_id, last_read_id, updated_at",
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(input.message_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(read)
+    }
+
+    /// Read receipts of the chat members
+    pub async fn list_chat_reads(&self, chat_id: u64) -> Result<Vec<ChatRead>, AppError> {
+        let reads = query_as(
+            "SELECT r.chat_id, r.user_id, r.last_read_id, r.updated_at FROM chat_reads r JOIN chats c ON c.id = r.chat_id WHERE r.chat_id = $1 AND r.user_id = ANY(c.members) ORDER BY r.user_id",
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(reads)
+    }
+}
+
+#[cfg(test)]
+impl MarkRead {
+    pub fn new(message_id: u64) -> Self {
+        Self { message_id }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateMessage;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn unread_counts_should_follow_read_position() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // user 2 has read nothing in chat 1
+        let chat = chat_summary(&state, 2, 1).await?;
+        let total = chat.unread_count;
+        assert!(total > 0);
+        assert_eq!(chat.mention_count, 0);
+
+        let message = state
+            .create_message(CreateMessage::new("hi @alice", vec![]), 1, 1)
+            .await?;
+        let chat = chat_summary(&state, 2, 1).await?;
+        assert_eq!(chat.unread_count, total + 1);
+        assert_eq!(chat.mention_count, 1);
+
+        // replies stay in their thread, the channel badge doesn't count them
+        let mut reply = CreateMessage::new("agreed @alice", vec![]);
+        reply.parent_id = Some(message.id as _);
+        state.create_message(reply, 1, 1).await?;
+        let chat = chat_summary(&state, 2, 1).await?;
+        assert_eq!(chat.unread_count, total + 1);
+        assert_eq!(chat.mention_count, 1);
+
+        let read = state
+            .mark_chat_read(&MarkRead::new(message.id as _), 1, 2)
+            .await?;
+        assert_eq!(read.last_read_id, message.id);
+        // marking an older message doesn't move the position back
+        let read = state.mark_chat_read(&MarkRead::new(1), 1, 2).await?;
+        assert_eq!(read.last_read_id, message.id);
+
+        let chat = chat_summary(&state, 2, 1).await?;
+        assert_eq!(chat.last_read_id, message.id);
+        assert_eq!(chat.unread_count, 0);
+        assert_eq!(chat.mention_count, 0);
+
+        let reads = state.list_chat_reads(1).await?;
+        assert_eq!(reads, vec![read]);
+        Ok(())
+    }
+
+    async fn chat_summary(state: &AppState, user_id: u64, chat_id: i64) -> Result<chat_core::Chat> {
+        let chats = state
+            .fetch_chat_all(&Default::default(), 1, user_id)
+            .await?;
+        Ok(chats.into_iter().find(|c| c.id == chat_id).unwrap())
+    }
+}
@@ -0,0 +1,284 @@
+use super::{generate_secret, hash_secret};
+use crate::{mailer::Mail, AppError, AppState};
+use chat_core::{User, Workspace, WorkspaceRole};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, FromRow, PgConnection};
+use utoipa::ToSchema;
+
+const DEFAULT_INVITE_HOURS: i64 = 24 * 7;
+const MAX_INVITE_HOURS: i64 = 24 * 30;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct WorkspaceInvite {
+    pub id: i64,
+    pub ws_id: i64,
+    pub inviter_id: i64,
+    pub email: Option<String>,
+    pub max_uses: Option<i32>,
+    pub use_count: i32,
+    pub role: WorkspaceRole,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct CreateInvite {
+    /// bind the invite to an email and mail it, otherwise a shareable link code is created
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub max_uses: Option<i32>,
+    #[serde(default)]
+    pub expires_in_hours: Option<i64>,
+    /// role given to people joining with the invite, defaults to member
+    #[serde(default)]
+    pub role: Option<WorkspaceRole>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AcceptInvite {
+    pub code: String,
+}
+
+#[derive(Debug, FromRow)]
+struct AcceptedInvite {
+    #[sqlx(flatten)]
+    ws: Workspace,
+    role: WorkspaceRole,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct InviteOutput {
+    pub invite: WorkspaceInvite,
+    /// only returned once, pass it as `invite_code` on signup
+    pub code: String,
+}
+
+impl AppState {
+    pub async fn create_invite(
+        &self,
+        inviter: &User,
+        inviter_role: WorkspaceRole,
+        input: &CreateInvite,
+    ) -> Result<InviteOutput, AppError> {
+        let role = input.role.unwrap_or(WorkspaceRole::Member);
+        if !inviter_role.outranks(role) {
+            return Err(AppError::PermissionDenied(format!(
+                "{:?} can't invite people as {:?}",
+                inviter_role, role
+            )));
+        }
+        let hours = input.expires_in_hours.unwrap_or(DEFAULT_INVITE_HOURS);
+        if !(1..=MAX_INVITE_HOURS).contains(&hours) {
+            return Err(AppError::InviteError(format!(
+                "expires_in_hours must be between 1 and {}",
+                MAX_INVITE_HOURS
+            )));
+        }
+        // an email bound invite is good for that one person only
+        let max_uses = match (&input.email, input.max_uses) {
+            (Some(_), _) => Some(1),
+            (None, Some(n)) if n < 1 => {
+                return Err(AppError::InviteError(
+                    "max_uses must be at least 1".to_string(),
+                ))
+            }
+            (None, n) => n,
+        };
+
+        let code = generate_secret();
+        let invite: WorkspaceInvite = query_as(
+            "INSERT INTO workspace_invites (ws_id, inviter_id, email, code_hash, max_uses, expires_at, role) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id, ws_id, inviter_id, email, max_uses, use_count, role, expires_at, created_at",
+        )
+        .bind(inviter.ws_id)
+        .bind(inviter.id)
+        .bind(&input.email)
+        .bind(hash_secret(&code))
+        .bind(max_uses)
+        .bind(Utc::now() + Duration::hours(hours))
+        .bind(role)
+        .fetch_one(&self.pool)
+        .await?;
+
+        if let Some(email) = &invite.email {
+            self.send_invite_email(inviter, email, &code).await?;
+        }
+
+        Ok(InviteOutput { invite, code })
+    }
+
+    /// Invites which can still be accepted
+    pub async fn list_pending_invites(&self, ws_id: u64) -> Result<Vec<WorkspaceInvite>, AppError> {
+        let invites = query_as(
+            "SELECT id, ws_id, inviter_id, email, max_uses, use_count, role, expires_at, created_at FROM workspace_invites WHERE ws_id = $1 AND revoked_at IS NULL AND expires_at > NOW() AND (max_uses IS NULL OR use_count < max_uses) ORDER BY created_at DESC",
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(invites)
+    }
+
+    pub async fn revoke_invite(&self, ws_id: u64, id: u64) -> Result<(), AppError> {
+        let ret = query("UPDATE workspace_invites SET revoked_at = NOW() WHERE id = $1 AND ws_id = $2 AND revoked_at IS NULL")
+            .bind(id as i64)
+            .bind(ws_id as i64)
+            .execute(&self.pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("invite id {}", id)));
+        }
+
+        Ok(())
+    }
+
+    /// Use up one seat of the invite, run inside the signup transaction
+    pub(crate) async fn accept_invite(
+        &self,
+        conn: &mut PgConnection,
+        code: &str,
+        email: &str,
+    ) -> Result<(Workspace, WorkspaceRole), AppError> {
+        let ret: Option<AcceptedInvite> = query_as(
+            "WITH i AS (UPDATE workspace_invites SET use_count = use_count + 1 WHERE code_hash = $1 AND revoked_at IS NULL AND expires_at > NOW() AND (max_uses IS NULL OR use_count < max_uses) AND (email IS NULL OR lower(email) = lower($2)) RETURNING ws_id, role) SELECT w.id, w.name, w.owner_id, w.created_at, i.role FROM workspaces w JOIN i ON w.id = i.ws_id",
+        )
+        .bind(hash_secret(code))
+        .bind(email)
+        .fetch_optional(conn)
+        .await?;
+
+        match ret {
+            Some(AcceptedInvite { ws, role }) => Ok((ws, role)),
+            None => Err(AppError::InviteError(
+                "invitation is invalid or expired".to_string(),
+            )),
+        }
+    }
+
+    /// Let an existing user join another workspace with an invite
+    pub async fn join_workspace(&self, user: &User, code: &str) -> Result<Workspace, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let (ws, role) = self.accept_invite(&mut tx, code, &user.email).await?;
+        if !self
+            .add_workspace_member(&mut tx, ws.id as _, user.id as _, role)
+            .await?
+        {
+            // dropping tx gives the seat back
+            return Err(AppError::InviteError(format!(
+                "already a member of workspace {}",
+                ws.name
+            )));
+        }
+        tx.commit().await?;
+
+        Ok(ws)
+    }
+
+    async fn send_invite_email(
+        &self,
+        inviter: &User,
+        email: &str,
+        code: &str,
+    ) -> Result<(), AppError> {
+        let link = format!("{}/signup?invite={}", self.config.mailer.app_url, code);
+        let body = format!(
+            "Hi,\n\n{} invited you to join the {} workspace. Open the link below to create your account:\n\n{}\n",
+            inviter.fullname, inviter.ws_name, link
+        );
+        let mail = Mail::new(email, format!("Join {} on Chat", inviter.ws_name), body);
+        self.mailer.send(&mail).await
+    }
+}
+
+#[cfg(test)]
+impl CreateInvite {
+    pub fn new(email: Option<&str>, max_uses: Option<i32>) -> Self {
+        Self {
+            email: email.map(|e| e.to_string()),
+            max_uses,
+            expires_in_hours: None,
+            role: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateUser;
+    use anyhow::Result;
+
+    async fn owner(state: &AppState) -> Result<User> {
+        let mut user = state.find_user_by_id(1).await?.expect("user should exist");
+        user.ws_name = "acme".to_string();
+        Ok(user)
+    }
+
+    #[tokio::test]
+    async fn link_invite_should_respect_max_uses() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = owner(&state).await?;
+        let ret = state
+            .create_invite(
+                &owner,
+                WorkspaceRole::Owner,
+                &CreateInvite::new(None, Some(1)),
+            )
+            .await?;
+
+        let input = CreateUser::new("", "tom", "tom@acme.org", "tom123").with_invite(&ret.code);
+        let user = state.create_user(&input).await?;
+        assert_eq!(user.ws_id, 1);
+        assert_eq!(user.ws_name, "acme");
+
+        let input =
+            CreateUser::new("", "jerry", "jerry@acme.org", "jerry123").with_invite(&ret.code);
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InviteError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn email_invite_should_only_accept_that_email() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = owner(&state).await?;
+        let ret = state
+            .create_invite(
+                &owner,
+                WorkspaceRole::Owner,
+                &CreateInvite::new(Some("Tom@acme.org"), None),
+            )
+            .await?;
+        assert_eq!(ret.invite.max_uses, Some(1));
+
+        let input =
+            CreateUser::new("", "jerry", "jerry@acme.org", "jerry123").with_invite(&ret.code);
+        assert!(state.create_user(&input).await.is_err());
+        // nothing was created by the failed signup
+        assert!(state.find_user_by_email("jerry@acme.org").await?.is_none());
+
+        let input = CreateUser::new("", "tom", "tom@acme.org", "tom123").with_invite(&ret.code);
+        let user = state.create_user(&input).await?;
+        assert_eq!(user.ws_id, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revoked_invite_should_not_be_pending() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = owner(&state).await?;
+        let ret = state
+            .create_invite(&owner, WorkspaceRole::Owner, &CreateInvite::default())
+            .await?;
+        let invites = state.list_pending_invites(1).await?;
+        assert_eq!(invites, vec![ret.invite.clone()]);
+
+        state.revoke_invite(1, ret.invite.id as _).await?;
+        assert!(state.list_pending_invites(1).await?.is_empty());
+
+        let input = CreateUser::new("", "tom", "tom@acme.org", "tom123").with_invite(&ret.code);
+        assert!(state.create_user(&input).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{AppError, AppState};
+use chat_core::WorkspaceRole;
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_scalar, PgConnection};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UpdateRole {
+    pub role: WorkspaceRole,
+}
+
+impl AppState {
+    pub async fn get_member_role(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<Option<WorkspaceRole>, AppError> {
+        let role =
+            query_scalar("SELECT role FROM workspace_members WHERE ws_id = $1 AND user_id = $2")
+                .bind(ws_id as i64)
+                .bind(user_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(role)
+    }
+
+    /// Return false if the user already is a member
+    pub(crate) async fn add_workspace_member(
+        &self,
+        conn: &mut PgConnection,
+        ws_id: u64,
+        user_id: u64,
+        role: WorkspaceRole,
+    ) -> Result<bool, AppError> {
+        let ret = query("INSERT INTO workspace_members (ws_id, user_id, role) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING")
+            .bind(ws_id as i64)
+            .bind(user_id as i64)
+            .bind(role)
+            .execute(conn)
+            .await?;
+
+        Ok(ret.rows_affected() == 1)
+    }
+
+    /// Fail unless the actor outranks every one of the users in the workspace
+    pub async fn require_outranks(
+        &self,
+        ws_id: u64,
+        actor_role: WorkspaceRole,
+        user_ids: &[i64],
+    ) -> Result<(), AppError> {
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+        let roles: Vec<WorkspaceRole> = query_scalar(
+            "SELECT role FROM workspace_members WHERE ws_id = $1 AND user_id = ANY($2)",
+        )
+        .bind(ws_id as i64)
+        .bind(user_ids)
+        .fetch_all(&self.pool)
+        .await?;
+        match roles.into_iter().find(|role| !actor_role.outranks(*role)) {
+            Some(role) => Err(AppError::PermissionDenied(format!(
+                "{:?} can't manage a {:?}",
+                actor_role, role
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Change the role of a member, admins may only manage members and guests
+    pub async fn update_member_role(
+        &self,
+        ws_id: u64,
+        actor_role: WorkspaceRole,
+        user_id: u64,
+        role: WorkspaceRole,
+    ) -> Result<WorkspaceRole, AppError> {
+        if role == WorkspaceRole::Owner {
+            return Err(AppError::PermissionDenied(
+                "ownership can't be granted by changing roles".to_string(),
+            ));
+        }
+        let Some(current) = self.get_member_role(ws_id, user_id).await? else {
+            return Err(AppError::NotFound(format!("member id {}", user_id)));
+        };
+        if !actor_role.outranks(current) || !actor_role.outranks(role) {
+            return Err(AppError::PermissionDenied(format!(
+                "{:?} can't change the role of {:?} to {:?}",
+                actor_role, current, role
+            )));
+        }
+
+        query("UPDATE workspace_members SET role = $1 WHERE ws_id = $2 AND user_id = $3")
+            .bind(role)
+            .bind(ws_id as i64)
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(role)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn get_member_role_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        assert_eq!(
+            state.get_member_role(1, 1).await?,
+            Some(WorkspaceRole::Owner)
+        );
+        assert_eq!(
+            state.get_member_role(1, 6).await?,
+            Some(WorkspaceRole::Guest)
+        );
+        assert_eq!(state.get_member_role(2, 1).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_member_role_should_respect_ranks() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let role = state
+            .update_member_role(1, WorkspaceRole::Admin, 6, WorkspaceRole::Member)
+            .await?;
+        assert_eq!(role, WorkspaceRole::Member);
+
+        // admins can't promote to admin, nor touch the owner
+        let ret = state
+            .update_member_role(1, WorkspaceRole::Admin, 3, WorkspaceRole::Admin)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state
+            .update_member_role(1, WorkspaceRole::Admin, 1, WorkspaceRole::Guest)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let role = state
+            .update_member_role(1, WorkspaceRole::Owner, 3, WorkspaceRole::Admin)
+            .await?;
+        assert_eq!(role, WorkspaceRole::Admin);
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{AppError, AppState};
+use chat_core::{Mention, MentionKind, Message};
+use sqlx::{query, query_as, query_scalar, FromRow, PgConnection};
+use std::collections::HashMap;
+
+#[derive(Debug, FromRow)]
+struct MessageMention {
+    message_id: i64,
+    #[sqlx(flatten)]
+    mention: Mention,
+}
+
+impl AppState {
+    /// Resolve the mentions in a message against the chat members and store them,
+    /// run inside the send or edit transaction
+    pub(crate) async fn save_mentions(
+        &self,
+        conn: &mut PgConnection,
+        message: &Message,
+    ) -> Result<Vec<Mention>, AppError> {
+        let mentions = resolve_mentions(conn, message).await?;
+        let (kinds, user_ids): (Vec<_>, Vec<_>) =
+            mentions.iter().map(|m| (m.kind, m.user_id)).unzip();
+
+        // an edit drops the mentions gone from the content and keeps the others,
+        // the trigger only reports the inserted ones so nobody is notified twice
+        if message.edited_at.is_some() {
+            query(
+                "DELETE FROM message_mentions mm WHERE mm.message_id = $1 AND NOT EXISTS (SELECT 1 FROM UNNEST($2::mention_kind[], $3::bigint[]) AS n(kind, user_id) WHERE n.kind = mm.kind AND n.user_id IS NOT DISTINCT FROM mm.user_id)",
+            )
+            .bind(message.id)
+            .bind(&kinds)
+            .bind(&user_ids)
+            .execute(&mut *conn)
+            .await?;
+        }
+        if mentions.is_empty() {
+            return Ok(mentions);
+        }
+
+        // a single statement so members are notified once per message
+        query(
+            "INSERT INTO message_mentions (message_id, kind, user_id) SELECT $1, * FROM UNNEST($2::mention_kind[], $3::bigint[]) ON CONFLICT DO NOTHING",
+        )
+        .bind(message.id)
+        .bind(kinds)
+        .bind(user_ids)
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(mentions)
+    }
+
+    /// Attach the mentions to a page of messages
+    pub(crate) async fn fill_mentions(&self, messages: &mut [Message]) -> Result<(), AppError> {
+        let ids: Vec<i64> = messages
+            .iter()
+            .filter(|m| !m.is_deleted())
+            .map(|m| m.id)
+            .collect();
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let rows: Vec<MessageMention> = query_as(
+            "SELECT message_id, kind, user_id FROM message_mentions WHERE message_id = ANY($1) ORDER BY kind, user_id",
+        )
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut mentions_by_message: HashMap<i64, Vec<Mention>> = HashMap::new();
+        for row in rows {
+            mentions_by_message
+                .entry(row.message_id)
+                .or_default()
+                .push(row.mention);
+        }
+        for message in messages.iter_mut() {
+            if let Some(mentions) = mentions_by_message.remove(&message.id) {
+                message.mentions = mentions;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Mentions in the content, people by the name part of their email among the chat members,
+/// a handle shared by several members is ambiguous and mentions nobody,
+/// `@here` is an alias of `@channel` as presence is only known to notify-server
+async fn resolve_mentions(
+    conn: &mut PgConnection,
+    message: &Message,
+) -> Result<Vec<Mention>, AppError> {
+    let handles = parse_mentions(&message.content);
+    if handles.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let user_ids: Vec<i64> = query_scalar(
+        "SELECT min(id) FROM users WHERE id = ANY(SELECT unnest(members) FROM chats WHERE id = $1) AND lower(split_part(email, '@', 1)) = ANY($2) GROUP BY lower(split_part(email, '@', 1)) HAVING count(*) = 1 ORDER BY 1",
+    )
+    .bind(message.chat_id)
+    .bind(&handles)
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut mentions: Vec<Mention> = user_ids
+        .into_iter()
+        .map(|id| Mention {
+            kind: MentionKind::User,
+            user_id: Some(id),
+        })
+        .collect();
+    mentions.extend(
+        handles
+            .iter()
+            .filter_map(|h| match h.as_str() {
+                "channel" => Some(MentionKind::Channel),
+                "here" => Some(MentionKind::Here),
+                _ => None,
+            })
+            .map(|kind| Mention {
+                kind,
+                user_id: None,
+            }),
+    );
+    Ok(mentions)
+}
+
+/// Lowercased handles following an `@`, in order of appearance without duplicates
+fn parse_mentions(content: &str) -> Vec<String> {
+    let is_handle_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '.' | '-');
+    let mut handles: Vec<String> = Vec::new();
+    for (i, _) in content.match_indices('@') {
+        // skip email addresses like tom@acme.org
+        if content[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_')
+        {
+            continue;
+        }
+        let handle: String = content[i + 1..]
+            .chars()
+            .take_while(|c| is_handle_char(*c))
+            .collect();
+        let handle = handle.trim_end_matches(['.', '-']).to_lowercase();
+        if !handle.is_empty() && !handles.contains(&handle) {
+            handles.push(handle);
+        }
+    }
+    handles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CreateMessage, ListMessage, UpdateMessage};
+    use anyhow::Result;
+    use sqlx::postgres::PgListener;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_mentions_should_work() {
+        let handles = parse_mentions("@Alice, ping @bob. mail tom@acme.org @alice @here!");
+        assert_eq!(handles, vec!["alice", "bob", "here"]);
+        assert!(parse_mentions("no mentions @ all").is_empty());
+    }
+
+    #[tokio::test]
+    async fn mentions_should_be_validated_against_members() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // charlie is not a member of chat 2
+        let input = CreateMessage::new("@alice @charlie @nobody @channel", vec![]);
+        let message = state.create_message(input, 2, 1).await?;
+        assert_eq!(
+            message.mentions,
+            vec![
+                Mention {
+                    kind: MentionKind::User,
+                    user_id: Some(2),
+                },
+                Mention {
+                    kind: MentionKind::Channel,
+                    user_id: None,
+                },
+            ]
+        );
+
+        let messages = state.list_message(ListMessage::new(None, 0), 2).await?;
+        let listed = messages.iter().find(|m| m.id == message.id).unwrap();
+        assert_eq!(listed.mentions, message.mentions);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ambiguous_handles_should_mention_nobody() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // alice and alice@other.org are both members of chat 2
+        sqlx::query("UPDATE users SET email = 'alice@other.org' WHERE id = 3")
+            .execute(&state.pool)
+            .await?;
+        let input = CreateMessage::new("@alice @nyh", vec![]);
+        let message = state.create_message(input, 2, 2).await?;
+        assert_eq!(
+            message.mentions,
+            vec![Mention {
+                kind: MentionKind::User,
+                user_id: Some(1),
+            }]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn edit_should_only_notify_new_mentions() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut listener = PgListener::connect_with(&state.pool).await?;
+        listener.listen("chat_message_mentioned").await?;
+        let message = state
+            .create_message(CreateMessage::new("@alice", vec![]), 2, 1)
+            .await?;
+        let notif = listener.recv().await?;
+        let payload: serde_json::Value = serde_json::from_str(notif.payload())?;
+        assert_eq!(payload["mentions"][0]["user_id"], 2);
+
+        state
+            .update_message(UpdateMessage::new("@alice @bob"), 2, message.id as _, 1)
+            .await?;
+        let notif = listener.recv().await?;
+        let payload: serde_json::Value = serde_json::from_str(notif.payload())?;
+        assert_eq!(payload["id"], message.id);
+        assert_eq!(
+            payload["mentions"],
+            serde_json::json!([{"kind": "user", "user_id": 3}])
+        );
+
+        // nobody new, nothing to notify
+        state
+            .update_message(UpdateMessage::new("@bob"), 2, message.id as _, 1)
+            .await?;
+        let ret = tokio::time::timeout(Duration::from_millis(200), listener.recv()).await;
+        assert!(ret.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn edited_mentions_should_be_stored() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateMessage::new("@alice @channel", vec![]);
+        let message = state.create_message(input, 2, 1).await?;
+
+        let message = state
+            .update_message(UpdateMessage::new("@bob @channel"), 2, message.id as _, 1)
+            .await?;
+        let expected = vec![
+            Mention {
+                kind: MentionKind::User,
+                user_id: Some(3),
+            },
+            Mention {
+                kind: MentionKind::Channel,
+                user_id: None,
+            },
+        ];
+        assert_eq!(message.mentions, expected);
+        let messages = state.list_message(ListMessage::new(None, 0), 2).await?;
+        let listed = messages.iter().find(|m| m.id == message.id).unwrap();
+        assert_eq!(listed.mentions, expected);
+
+        let message = state
+            .update_message(UpdateMessage::new("no one"), 2, message.id as _, 1)
+            .await?;
+        assert!(message.mentions.is_empty());
+        let messages = state.list_message(ListMessage::new(None, 0), 2).await?;
+        let listed = messages.iter().find(|m| m.id == message.id).unwrap();
+        assert!(listed.mentions.is_empty());
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
 use super::ChatFile;
 use crate::{AppError, AppState};
 use chat_core::Message;
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::query_as;
+use sqlx::{query, query_as, query_scalar, FromRow};
 use std::str::FromStr;
 use utoipa::{IntoParams, ToSchema};
 
@@ -11,6 +12,9 @@ pub struct CreateMessage {
     pub content: String,
     #[serde(default)]
     pub files: Vec<String>,
+    /// reply in the thread of this message
+    #[serde(default)]
+    pub parent_id: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema, IntoParams)]
@@ -21,6 +25,21 @@ pub struct ListMessage {
     pub limit: u64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UpdateMessage {
+    pub content: String,
+}
+
+/// A prior revision of an edited message
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
+pub struct MessageRevision {
+    pub id: i64,
+    pub message_id: i64,
+    pub content: String,
+    pub files: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema, IntoParams)]
 pub struct DeleteMessage {
     pub message_id: u64,
@@ -53,31 +72,162 @@ impl AppState {
             }
         }
 
+        let mut tx = self.pool.begin().await?;
+        // shared lock, so the chat can't be archived while the message is sent
+        let archived: Option<bool> =
+            query_scalar("SELECT archived_at IS NOT NULL FROM chats WHERE id = $1 FOR SHARE")
+                .bind(chat_id as i64)
+                .fetch_optional(&mut *tx)
+                .await?;
+        if archived.unwrap_or_default() {
+            return Err(AppError::CreateMessageError(format!(
+                "chat {} is archived",
+                chat_id
+            )));
+        }
+        if let Some(parent_id) = input.parent_id {
+            // bump the parent first so the reply notification carries the new count,
+            // threads are one level deep
+            let ret = query("UPDATE messages SET reply_count = reply_count + 1, last_reply_at = NOW() WHERE id = $1 AND chat_id = $2 AND parent_id IS NULL AND deleted_at IS NULL")
+                .bind(parent_id as i64)
+                .bind(chat_id as i64)
+                .execute(&mut *tx)
+                .await?;
+            if ret.rows_affected() == 0 {
+                return Err(AppError::CreateMessageError(format!(
+                    "Message {} can't be replied to",
+                    parent_id
+                )));
+            }
+        }
+
         // crate message
-        let message: Message = query_as(
-            "INSERT INTO messages (chat_id, sender_id, content, files) VALUES ($1, $2, $3, $4) RETURNING id, chat_id, sender_id, content, files, created_at",
+        let mut message: Message = query_as(
+            "INSERT INTO messages (chat_id, sender_id, content, files, parent_id) VALUES ($1, $2, $3, $4, $5) RETURNING id, chat_id, sender_id, content, files, created_at, edited_at, deleted_at, deleted_by, parent_id, reply_count, last_reply_at",
         )
         .bind(chat_id as i64)
         .bind(user_id as i64)
         .bind(&input.content)
         .bind(&input.files)
-        .fetch_one(&self.pool)
+        .bind(input.parent_id.map(|id| id as i64))
+        .fetch_one(&mut *tx)
         .await?;
+        message.mentions = self.save_mentions(&mut tx, &message).await?;
+        tx.commit().await?;
 
         Ok(message)
     }
 
+    pub async fn find_message(&self, chat_id: u64, id: u64) -> Result<Option<Message>, AppError> {
+        let message: Option<Message> = query_as("SELECT id, chat_id, sender_id, content, files, created_at, edited_at, deleted_at, deleted_by, parent_id, reply_count, last_reply_at FROM messages WHERE id = $1 AND chat_id = $2")
+            .bind(id as i64)
+            .bind(chat_id as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(message.map(Message::into_tombstone))
+    }
+
+    /// Only the sender may edit, and only within the configured window
+    pub async fn update_message(
+        &self,
+        input: UpdateMessage,
+        chat_id: u64,
+        id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        if input.content.is_empty() {
+            return Err(AppError::UpdateMessageError(
+                "content is required".to_string(),
+            ));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let message: Option<Message> = query_as("SELECT id, chat_id, sender_id, content, files, created_at, edited_at, deleted_at, deleted_by, parent_id, reply_count, last_reply_at FROM messages WHERE id = $1 AND chat_id = $2 FOR UPDATE")
+            .bind(id as i64)
+            .bind(chat_id as i64)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(message) = message.filter(|m| !m.is_deleted()) else {
+            return Err(AppError::NotFound(format!("message id {}", id)));
+        };
+        if message.sender_id != user_id as i64 {
+            return Err(AppError::PermissionDenied(
+                "only the sender can edit a message".to_string(),
+            ));
+        }
+        let window = Duration::seconds(self.config.message.edit_window_secs as _);
+        if message.created_at + window < Utc::now() {
+            return Err(AppError::UpdateMessageError(
+                "message can no longer be edited".to_string(),
+            ));
+        }
+        if message.content == input.content {
+            return Ok(message);
+        }
+
+        query("INSERT INTO message_history (message_id, content, files) VALUES ($1, $2, $3)")
+            .bind(message.id)
+            .bind(&message.content)
+            .bind(&message.files)
+            .execute(&mut *tx)
+            .await?;
+        let mut message: Message = query_as("UPDATE messages SET content = $1, edited_at = NOW() WHERE id = $2 RETURNING id, chat_id, sender_id, content, files, created_at, edited_at, deleted_at, deleted_by, parent_id, reply_count, last_reply_at")
+            .bind(&input.content)
+            .bind(message.id)
+            .fetch_one(&mut *tx)
+            .await?;
+        message.mentions = self.save_mentions(&mut tx, &message).await?;
+        tx.commit().await?;
+
+        Ok(message)
+    }
+
+    /// Prior revisions of a message, oldest first
+    pub async fn list_message_history(
+        &self,
+        chat_id: u64,
+        id: u64,
+    ) -> Result<Vec<MessageRevision>, AppError> {
+        let revisions = query_as("SELECT h.id, h.message_id, h.content, h.files, h.created_at FROM message_history h JOIN messages m ON m.id = h.message_id WHERE h.message_id = $1 AND m.chat_id = $2 AND m.deleted_at IS NULL ORDER BY h.id")
+            .bind(id as i64)
+            .bind(chat_id as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(revisions)
+    }
+
+    /// Soft delete, the message stays in the chat as a tombstone
     pub async fn delete_message(
         &self,
         input: DeleteMessage,
         chat_id: u64,
+        user_id: u64,
     ) -> Result<Message, AppError> {
-        let message: Message = query_as("DELETE FROM messages WHERE id = $1 AND chat_id = $2 RETURNING id, chat_id, sender_id, content, files, created_at")
+        let mut tx = self.pool.begin().await?;
+        let message: Option<Message> = query_as("UPDATE messages SET deleted_at = NOW(), deleted_by = $3 WHERE id = $1 AND chat_id = $2 AND deleted_at IS NULL RETURNING id, chat_id, sender_id, content, files, created_at, edited_at, deleted_at, deleted_by, parent_id, reply_count, last_reply_at")
             .bind(input.message_id as i64)
             .bind(chat_id as i64)
-            .fetch_one(&self.pool)
+            .bind(user_id as i64)
+            .fetch_optional(&mut *tx)
             .await?;
-        Ok(message)
+        let Some(message) = message else {
+            return Err(AppError::NotFound(format!(
+                "message id {}",
+                input.message_id
+            )));
+        };
+        if let Some(parent_id) = message.parent_id {
+            // the thread summary only counts the replies still there
+            query("UPDATE messages SET reply_count = GREATEST(reply_count - 1, 0), last_reply_at = (SELECT MAX(created_at) FROM messages WHERE parent_id = $1 AND deleted_at IS NULL) WHERE id = $1")
+                .bind(parent_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(message.into_tombstone())
     }
 
     pub async fn list_message(
@@ -94,14 +244,53 @@ impl AppState {
         };
 
         let messages: Vec<Message> = query_as(
-            "SELECT id, chat_id, sender_id, content, files, created_at FROM messages WHERE chat_id = $1 AND id < $2 ORDER BY id ASC LIMIT $3",
+            "SELECT id, chat_id, sender_id, content, files, created_at, edited_at, deleted_at, deleted_by, parent_id, reply_count, last_reply_at FROM messages WHERE chat_id = $1 AND parent_id IS NULL AND id < $2 ORDER BY id ASC LIMIT $3",
+        )
+        .bind(chat_id as i64)
+        .bind(last_id as i64)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages: Vec<Message> =
+            messages.into_iter().map(Message::into_tombstone).collect();
+        self.fill_reaction_counts(&mut messages).await?;
+        self.fill_mentions(&mut messages).await?;
+        Ok(messages)
+    }
+
+    /// Replies in the thread of a message
+    pub async fn list_thread(
+        &self,
+        input: ListMessage,
+        chat_id: u64,
+        parent_id: u64,
+    ) -> Result<Vec<Message>, AppError> {
+        if self.find_message(chat_id, parent_id).await?.is_none() {
+            return Err(AppError::NotFound(format!("message id {}", parent_id)));
+        }
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+
+        let limit = match input.limit {
+            0 => i64::MAX,
+            1..=100 => input.limit as _,
+            _ => 100,
+        };
+
+        let messages: Vec<Message> = query_as(
+            "SELECT id, chat_id, sender_id, content, files, created_at, edited_at, deleted_at, deleted_by, parent_id, reply_count, last_reply_at FROM messages WHERE chat_id = $1 AND parent_id = $2 AND id < $3 ORDER BY id ASC LIMIT $4",
         )
         .bind(chat_id as i64)
+        .bind(parent_id as i64)
         .bind(last_id as i64)
         .bind(limit)
         .fetch_all(&self.pool)
         .await?;
 
+        let mut messages: Vec<Message> =
+            messages.into_iter().map(Message::into_tombstone).collect();
+        self.fill_reaction_counts(&mut messages).await?;
+        self.fill_mentions(&mut messages).await?;
         Ok(messages)
     }
 }
@@ -112,8 +301,14 @@ impl CreateMessage {
         Self {
             content: content.into(),
             files: files.into_iter().map(|s| s.into()).collect(),
+            parent_id: None,
         }
     }
+
+    pub fn reply_to(mut self, parent_id: u64) -> Self {
+        self.parent_id = Some(parent_id);
+        self
+    }
 }
 
 #[cfg(test)]
@@ -123,6 +318,15 @@ impl ListMessage {
     }
 }
 
+#[cfg(test)]
+impl UpdateMessage {
+    pub fn new(content: impl Into<String>) -> Self {
+        Self {
+            content: content.into(),
+        }
+    }
+}
+
 #[cfg(test)]
 impl DeleteMessage {
     pub fn new(message_id: u64) -> Self {
@@ -133,6 +337,7 @@ impl DeleteMessage {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::UpdateChatInfo;
     use anyhow::Result;
 
     #[tokio::test]
@@ -152,6 +357,113 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn archived_chat_should_be_read_only() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateChatInfo {
+            archived: Some(true),
+            ..Default::default()
+        };
+        state.update_chat_info(2, 1, &input).await?;
+        let ret = state
+            .create_message(CreateMessage::new("hello", vec![]), 2, 2)
+            .await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+
+        let input = UpdateChatInfo {
+            archived: Some(false),
+            ..Default::default()
+        };
+        state.update_chat_info(2, 1, &input).await?;
+        state
+            .create_message(CreateMessage::new("hello", vec![]), 2, 2)
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reply_should_update_parent_thread() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let parent = state
+            .create_message(CreateMessage::new("topic", vec![]), 2, 1)
+            .await?;
+        let input = CreateMessage::new("reply", vec![]).reply_to(parent.id as _);
+        let reply = state.create_message(input, 2, 2).await?;
+        assert_eq!(reply.parent_id, Some(parent.id));
+
+        // no replies to replies
+        let input = CreateMessage::new("nested", vec![]).reply_to(reply.id as _);
+        let ret = state.create_message(input, 2, 2).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+
+        // replies stay out of the chat, the parent shows the thread summary
+        let messages = state.list_message(ListMessage::new(None, 0), 2).await?;
+        assert!(messages.iter().all(|m| m.parent_id.is_none()));
+        let parent = messages.iter().find(|m| m.id == parent.id).unwrap();
+        assert_eq!(parent.reply_count, 1);
+        assert!(parent.last_reply_at.is_some());
+
+        let thread = state
+            .list_thread(ListMessage::new(None, 0), 2, parent.id as _)
+            .await?;
+        assert_eq!(thread, vec![reply.clone()]);
+
+        // deleted replies leave the summary, the last reply time falls back to the one before
+        let input = CreateMessage::new("another", vec![]).reply_to(parent.id as _);
+        let another = state.create_message(input, 2, 1).await?;
+        state
+            .delete_message(DeleteMessage::new(another.id as _), 2, 1)
+            .await?;
+        let parent = state.find_message(2, parent.id as _).await?.unwrap();
+        assert_eq!(parent.reply_count, 1);
+        assert_eq!(parent.last_reply_at, Some(reply.created_at));
+
+        state
+            .delete_message(DeleteMessage::new(reply.id as _), 2, 2)
+            .await?;
+        let parent = state.find_message(2, parent.id as _).await?.unwrap();
+        assert_eq!(parent.reply_count, 0);
+        assert!(parent.last_reply_at.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_message_should_keep_history() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateMessage::new("helo", vec![]);
+        let message = state.create_message(input, 2, 2).await?;
+        assert!(message.edited_at.is_none());
+
+        let ret = state
+            .update_message(UpdateMessage::new("hi"), 2, message.id as _, 1)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let updated = state
+            .update_message(UpdateMessage::new("hello"), 2, message.id as _, 2)
+            .await?;
+        assert_eq!(updated.content, "hello");
+        assert!(updated.edited_at.is_some());
+
+        let history = state.list_message_history(2, message.id as _).await?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].content, "helo");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_message_after_window_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        query("UPDATE messages SET created_at = NOW() - interval '1 day' WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let ret = state
+            .update_message(UpdateMessage::new("hello"), 1, 1, 1)
+            .await;
+        assert!(matches!(ret, Err(AppError::UpdateMessageError(_))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn delete_message_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -159,8 +471,20 @@ mod tests {
         let input = CreateMessage::new("hello", vec![&url]);
         let message = state.create_message(input, 2, 2).await?;
         let input = DeleteMessage::new(message.id as _);
-        let message1 = state.delete_message(input, 2).await?;
-        assert_eq!(message1, message);
+        let message1 = state.delete_message(input.clone(), 2, 2).await?;
+        assert_eq!(message1.id, message.id);
+        assert_eq!(message1.deleted_by, Some(2));
+        assert!(message1.content.is_empty());
+        assert!(message1.files.is_empty());
+
+        // the tombstone is still listed, without content
+        let messages = state.list_message(ListMessage::new(None, 0), 2).await?;
+        let tombstone = messages.iter().find(|m| m.id == message.id).unwrap();
+        assert!(tombstone.is_deleted());
+        assert!(tombstone.content.is_empty());
+
+        let ret = state.delete_message(input, 2, 2).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
         Ok(())
     }
 
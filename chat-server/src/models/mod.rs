@@ -1,14 +1,33 @@
+mod channel;
 mod chat;
+mod chat_read;
 mod file;
+mod invite;
+mod member;
+mod mention;
 mod message;
+mod reaction;
+mod session;
 mod user;
+mod user_token;
 mod workspace;
 
-pub use chat::ParamChat;
-pub use message::{CreateMessage, DeleteMessage, ListMessage};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+pub use channel::{ChannelSummary, ListChannels};
+pub use chat::{ChatMembers, CreateDirectChat, ListChats, ParamChat, UpdateChatInfo};
+pub use chat_read::MarkRead;
+pub use invite::{AcceptInvite, CreateInvite, InviteOutput, WorkspaceInvite};
+pub use member::UpdateRole;
+pub use message::{CreateMessage, DeleteMessage, ListMessage, MessageRevision, UpdateMessage};
+pub use reaction::CreateReaction;
 use serde::{Deserialize, Serialize};
+pub use session::RefreshToken;
+use sha2::{Digest, Sha256};
 pub use user::{CreateUser, SigninUser};
+pub use user_token::{ForgotPassword, ResetPassword, VerifyEmail};
 use utoipa::ToSchema;
+pub use workspace::{UpdateWorkspace, UserWorkspace};
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct ChatFile {
@@ -16,3 +35,14 @@ pub struct ChatFile {
     pub ext: String, // extract ext from filename or use mime type
     pub hash: String,
 }
+
+/// random secret for tokens handed out to users, only its hash is stored
+fn generate_secret() -> String {
+    let mut buf = [0u8; 32];
+    OsRng.fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
@@ -0,0 +1,235 @@
+use crate::{AppError, AppState};
+use chat_core::{Message, Reaction, ReactionCount};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, FromRow};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+const MAX_EMOJI_LEN: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreateReaction {
+    /// shortcode like `:+1:` or the emoji itself
+    pub emoji: String,
+}
+
+#[derive(Debug, FromRow)]
+struct MessageReactionCount {
+    message_id: i64,
+    #[sqlx(flatten)]
+    count: ReactionCount,
+}
+
+impl AppState {
+    /// React to a message, reacting twice with the same emoji is a no-op
+    pub async fn add_reaction(
+        &self,
+        input: &CreateReaction,
+        chat_id: u64,
+        message_id: u64,
+        user_id: u64,
+    ) -> Result<Reaction, AppError> {
+        let emoji = input.emoji.trim();
+        if emoji.is_empty()
+            || emoji.chars().count() > MAX_EMOJI_LEN
+            || emoji.contains(char::is_whitespace)
+        {
+            return Err(AppError::ReactionError(format!(
+                "invalid emoji {:?}",
+                input.emoji
+            )));
+        }
+        match self.find_message(chat_id, message_id).await? {
+            Some(message) if !message.is_deleted() => {}
+            _ => return Err(AppError::NotFound(format!("message id {}", message_id))),
+        }
+
+        let reaction: Option<Reaction> = query_as(
+            "INSERT INTO message_reactions (message_id, user_id, emoji) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING RETURNING message_id, user_id, emoji, created_at",
+        )
+        .bind(message_id as i64)
+        .bind(user_id as i64)
+        .bind(emoji)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match reaction {
+            Some(reaction) => Ok(reaction),
+            None => {
+                let reaction = query_as(
+                    "SELECT message_id, user_id, emoji, created_at FROM message_reactions WHERE message_id = $1 AND user_id = $2 AND emoji = $3",
+                )
+                .bind(message_id as i64)
+                .bind(user_id as i64)
+                .bind(emoji)
+                .fetch_one(&self.pool)
+                .await?;
+                Ok(reaction)
+            }
+        }
+    }
+
+    pub async fn remove_reaction(
+        &self,
+        chat_id: u64,
+        message_id: u64,
+        user_id: u64,
+        emoji: &str,
+    ) -> Result<(), AppError> {
+        let ret = query("DELETE FROM message_reactions r USING messages m WHERE r.message_id = m.id AND m.chat_id = $1 AND r.message_id = $2 AND r.user_id = $3 AND r.emoji = $4")
+            .bind(chat_id as i64)
+            .bind(message_id as i64)
+            .bind(user_id as i64)
+            .bind(emoji)
+            .execute(&self.pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!(
+                "reaction {} on message id {}",
+                emoji, message_id
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub async fn list_reactions(
+        &self,
+        chat_id: u64,
+        message_id: u64,
+    ) -> Result<Vec<Reaction>, AppError> {
+        match self.find_message(chat_id, message_id).await? {
+            Some(message) if !message.is_deleted() => {}
+            Some(_) => return Ok(vec![]),
+            None => return Err(AppError::NotFound(format!("message id {}", message_id))),
+        }
+
+        let reactions = query_as(
+            "SELECT message_id, user_id, emoji, created_at FROM message_reactions WHERE message_id = $1 ORDER BY created_at ASC",
+        )
+        .bind(message_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(reactions)
+    }
+
+    /// Attach the reaction counts to a page of messages
+    pub(crate) async fn fill_reaction_counts(
+        &self,
+        messages: &mut [Message],
+    ) -> Result<(), AppError> {
+        let ids: Vec<i64> = messages
+            .iter()
+            .filter(|m| !m.is_deleted())
+            .map(|m| m.id)
+            .collect();
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let counts: Vec<MessageReactionCount> = query_as(
+            "SELECT message_id, emoji, COUNT(*) AS count, array_agg(user_id ORDER BY created_at) AS users FROM message_reactions WHERE message_id = ANY($1) GROUP BY message_id, emoji ORDER BY MIN(created_at)",
+        )
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut counts_by_message: HashMap<i64, Vec<ReactionCount>> = HashMap::new();
+        for c in counts {
+            counts_by_message
+                .entry(c.message_id)
+                .or_default()
+                .push(c.count);
+        }
+        for message in messages.iter_mut() {
+            if let Some(counts) = counts_by_message.remove(&message.id) {
+                message.reactions = counts;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl CreateReaction {
+    pub fn new(emoji: impl Into<String>) -> Self {
+        Self {
+            emoji: emoji.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{DeleteMessage, ListMessage};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn reactions_should_be_counted_on_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state
+            .add_reaction(&CreateReaction::new(":+1:"), 1, 1, 1)
+            .await?;
+        // reacting twice doesn't count twice
+        state
+            .add_reaction(&CreateReaction::new(":+1:"), 1, 1, 1)
+            .await?;
+        state
+            .add_reaction(&CreateReaction::new(":+1:"), 1, 1, 2)
+            .await?;
+        state
+            .add_reaction(&CreateReaction::new("🎉"), 1, 1, 3)
+            .await?;
+
+        let reactions = state.list_reactions(1, 1).await?;
+        assert_eq!(reactions.len(), 3);
+
+        let messages = state.list_message(ListMessage::new(None, 0), 1).await?;
+        let message = messages.iter().find(|m| m.id == 1).unwrap();
+        assert_eq!(
+            message.reactions,
+            vec![
+                ReactionCount {
+                    emoji: ":+1:".to_string(),
+                    count: 2,
+                    users: vec![1, 2],
+                },
+                ReactionCount {
+                    emoji: "🎉".to_string(),
+                    count: 1,
+                    users: vec![3],
+                },
+            ]
+        );
+
+        state.remove_reaction(1, 1, 2, ":+1:").await?;
+        let ret = state.remove_reaction(1, 1, 2, ":+1:").await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        assert_eq!(state.list_reactions(1, 1).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reaction_should_be_validated() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = state
+            .add_reaction(&CreateReaction::new("thumbs up"), 1, 1, 1)
+            .await;
+        assert!(matches!(ret, Err(AppError::ReactionError(_))));
+
+        // no reacting to messages in other chats or deleted ones
+        let ret = state
+            .add_reaction(&CreateReaction::new(":+1:"), 2, 1, 1)
+            .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        state.delete_message(DeleteMessage::new(1), 1, 1).await?;
+        let ret = state
+            .add_reaction(&CreateReaction::new(":+1:"), 1, 1, 1)
+            .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use super::{generate_secret, hash_secret};
+use crate::{AppError, AppState};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, FromRow};
+use utoipa::ToSchema;
+
+const REFRESH_TOKEN_DURATION: i64 = 60 * 60 * 24 * 30;
+
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct Session {
+    pub id: i64,
+    pub user_id: i64,
+    /// the workspace tokens of this session are scoped to
+    pub ws_id: i64,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct RefreshToken {
+    pub refresh_token: String,
+}
+
+impl AppState {
+    /// Create a new session for the user in the workspace, return it along with its refresh token
+    pub async fn create_session(
+        &self,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<(Session, String), AppError> {
+        let secret = generate_secret();
+        let session: Session = query_as(
+            "INSERT INTO sessions (user_id, ws_id, token_hash, expires_at) VALUES ($1, $2, $3, $4) RETURNING id, user_id, ws_id, expires_at, revoked_at, created_at",
+        )
+        .bind(user_id as i64)
+        .bind(ws_id as i64)
+        .bind(hash_secret(&secret))
+        .bind(Utc::now() + Duration::seconds(REFRESH_TOKEN_DURATION))
+        .fetch_one(&self.pool)
+        .await?;
+
+        let token = format!("{}.{}", session.id, secret);
+        Ok((session, token))
+    }
+
+    /// Exchange a refresh token for a new one, the old token can't be used again
+    pub async fn rotate_session(&self, refresh_token: &str) -> Result<(Session, String), AppError> {
+        self.rotate_session_into(refresh_token, None).await
+    }
+
+    /// Rotate the refresh token and move this session alone to another workspace
+    pub async fn switch_session(
+        &self,
+        refresh_token: &str,
+        ws_id: u64,
+    ) -> Result<(Session, String), AppError> {
+        self.rotate_session_into(refresh_token, Some(ws_id)).await
+    }
+
+    async fn rotate_session_into(
+        &self,
+        refresh_token: &str,
+        ws_id: Option<u64>,
+    ) -> Result<(Session, String), AppError> {
+        let (id, secret) = parse_refresh_token(refresh_token)?;
+        let token_hash = hash_secret(secret);
+        let new_secret = generate_secret();
+
+        let session: Option<Session> = query_as(
+            "UPDATE sessions SET prev_token_hash = token_hash, token_hash = $1, expires_at = $2, ws_id = COALESCE($5, ws_id), updated_at = NOW() WHERE id = $3 AND token_hash = $4 AND revoked_at IS NULL AND expires_at > NOW() RETURNING id, user_id, ws_id, expires_at, revoked_at, created_at",
+        )
+        .bind(hash_secret(&new_secret))
+        .bind(Utc::now() + Duration::seconds(REFRESH_TOKEN_DURATION))
+        .bind(id)
+        .bind(&token_hash)
+        .bind(ws_id.map(|id| id as i64))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match session {
+            Some(session) => {
+                let token = format!("{}.{}", session.id, new_secret);
+                Ok((session, token))
+            }
+            None => {
+                // a rotated token showing up again means it leaked, kill the whole session
+                query("UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND prev_token_hash = $2 AND revoked_at IS NULL")
+                    .bind(id)
+                    .bind(&token_hash)
+                    .execute(&self.pool)
+                    .await?;
+                Err(AppError::InvalidRefreshToken)
+            }
+        }
+    }
+
+    /// Look up the active session of a refresh token without rotating it
+    pub async fn find_session_by_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<Session>, AppError> {
+        let (id, secret) = parse_refresh_token(refresh_token)?;
+        let session = query_as(
+            "SELECT id, user_id, ws_id, expires_at, revoked_at, created_at FROM sessions WHERE id = $1 AND token_hash = $2 AND revoked_at IS NULL AND expires_at > NOW()",
+        )
+        .bind(id)
+        .bind(hash_secret(secret))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Revoke the session the refresh token belongs to
+    pub async fn revoke_session_by_token(&self, refresh_token: &str) -> Result<(), AppError> {
+        let (id, secret) = parse_refresh_token(refresh_token)?;
+        let ret = query(
+            "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND token_hash = $2 AND revoked_at IS NULL",
+        )
+        .bind(id)
+        .bind(hash_secret(secret))
+        .execute(&self.pool)
+        .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::InvalidRefreshToken);
+        }
+
+        Ok(())
+    }
+
+    /// Revoke every session of the user, e.g. after the password changed
+    pub async fn revoke_user_sessions(&self, user_id: u64) -> Result<(), AppError> {
+        query("UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL")
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn is_session_active(&self, id: u64) -> Result<bool, AppError> {
+        let session = query(
+            "SELECT 1 FROM sessions WHERE id = $1 AND revoked_at IS NULL AND expires_at > NOW()",
+        )
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(session.is_some())
+    }
+}
+
+/// refresh token looks like `{session_id}.{secret}`
+fn parse_refresh_token(token: &str) -> Result<(i64, &str), AppError> {
+    let Some((id, secret)) = token.split_once('.') else {
+        return Err(AppError::InvalidRefreshToken);
+    };
+
+    let Ok(id) = id.parse::<i64>() else {
+        return Err(AppError::InvalidRefreshToken);
+    };
+
+    Ok((id, secret))
+}
+
+#[cfg(test)]
+impl RefreshToken {
+    pub fn new(refresh_token: &str) -> Self {
+        Self {
+            refresh_token: refresh_token.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn create_session_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (session, token) = state.create_session(1, 1).await?;
+        assert_eq!(session.user_id, 1);
+        assert!(token.starts_with(&format!("{}.", session.id)));
+        assert!(state.is_session_active(session.id as _).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotate_session_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (session, token) = state.create_session(1, 1).await?;
+        let (session1, token1) = state.rotate_session(&token).await?;
+        assert_eq!(session.id, session1.id);
+        assert_ne!(token, token1);
+
+        // the new token keeps working
+        let (_, token2) = state.rotate_session(&token1).await?;
+        assert!(state.is_session_active(session.id as _).await?);
+
+        // bad token is rejected but doesn't affect the session
+        let ret = state.rotate_session(&format!("{}.bad", session.id)).await;
+        assert!(matches!(ret, Err(AppError::InvalidRefreshToken)));
+        assert!(state.is_session_active(session.id as _).await?);
+
+        // reusing a rotated token revokes the session
+        let ret = state.rotate_session(&token1).await;
+        assert!(matches!(ret, Err(AppError::InvalidRefreshToken)));
+        assert!(!state.is_session_active(session.id as _).await?);
+        let ret = state.rotate_session(&token2).await;
+        assert!(matches!(ret, Err(AppError::InvalidRefreshToken)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revoke_session_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (session, token) = state.create_session(1, 1).await?;
+        state.revoke_session_by_token(&token).await?;
+        assert!(!state.is_session_active(session.id as _).await?);
+
+        let ret = state.revoke_session_by_token(&token).await;
+        assert!(matches!(ret, Err(AppError::InvalidRefreshToken)));
+        let ret = state.rotate_session(&token).await;
+        assert!(matches!(ret, Err(AppError::InvalidRefreshToken)));
+        Ok(())
+    }
+}
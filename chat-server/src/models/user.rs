@@ -3,11 +3,11 @@ use argon2::{
     password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
     Argon2, PasswordHash, PasswordVerifier,
 };
-use chat_core::{ChatUser, User};
+use chat_core::{ChatUser, User, WorkspaceRole};
 #[allow(unused)]
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use sqlx::query_as;
+use sqlx::{query, query_as};
 use std::mem;
 use utoipa::ToSchema;
 
@@ -15,8 +15,13 @@ use utoipa::ToSchema;
 pub struct CreateUser {
     pub fullname: String,
     pub email: String,
+    /// name of the workspace to create, ignored when joining with an invite
+    #[serde(default)]
     pub workspace: String,
     pub password: String,
+    /// code of an invitation to an existing workspace
+    #[serde(default)]
+    pub invite_code: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
@@ -48,18 +53,33 @@ impl AppState {
         Ok(user)
     }
 
-    /// Create a new user
-    // TODO: use transaction for workspace and user creation
+    /// Create a new user, either joining a workspace by invite or creating a new one
     pub async fn create_user(&self, input: &CreateUser) -> anyhow::Result<User, AppError> {
         let user = self.find_user_by_email(&input.email).await?;
         if user.is_some() {
             return Err(AppError::EmailAlreadyExists(input.email.clone()));
         }
 
-        // check if workspace exists, if not create one
-        let ws = match self.find_workspace_by_name(&input.workspace).await? {
-            Some(ws) => ws,
-            None => self.create_workspace(&input.workspace, 0).await?,
+        let mut tx = self.pool.begin().await?;
+        let (ws, role) = match &input.invite_code {
+            Some(code) => self.accept_invite(&mut tx, code, &input.email).await?,
+            None => {
+                if input.workspace.is_empty() {
+                    return Err(AppError::InviteError(
+                        "workspace name or invite code is required".to_string(),
+                    ));
+                }
+                // joining an existing workspace needs an invitation
+                if self
+                    .find_workspace_by_name(&input.workspace)
+                    .await?
+                    .is_some()
+                {
+                    return Err(AppError::WorkspaceAlreadyExists(input.workspace.clone()));
+                }
+                let ws = self.create_workspace(&mut tx, &input.workspace, 0).await?;
+                (ws, WorkspaceRole::Owner)
+            }
         };
 
         let password = hash_password(&input.password)?;
@@ -70,15 +90,17 @@ impl AppState {
         .bind(&input.email)
         .bind(&input.fullname)
         .bind(password)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
-
-        user.ws_name = ws.name;
-
+        self.add_workspace_member(&mut tx, ws.id as _, user.id as _, role)
+            .await?;
         if ws.owner_id == 0 {
-            self.update_workspace_owner(ws.id as _, user.id as _)
+            self.update_workspace_owner(&mut tx, ws.id as _, user.id as _)
                 .await?;
         }
+        tx.commit().await?;
+
+        user.ws_name = ws.name;
 
         Ok(user)
     }
@@ -110,8 +132,34 @@ impl AppState {
         }
     }
 
-    pub async fn fetch_chat_user_by_ids(&self, ids: &[i64]) -> Result<Vec<ChatUser>, AppError> {
-        let users = query_as("SELECT id, fullname, email FROM users WHERE id = ANY($1)")
+    pub async fn update_password(&self, user_id: u64, password: &str) -> Result<(), AppError> {
+        let password = hash_password(password)?;
+        query("UPDATE users SET password_hash = $1 WHERE id = $2")
+            .bind(password)
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn mark_email_verified(&self, user_id: u64) -> Result<(), AppError> {
+        query("UPDATE users SET email_verified_at = NOW() WHERE id = $1 AND email_verified_at IS NULL")
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Users of the workspace among the ids, users of other workspaces are left out
+    pub async fn fetch_chat_user_by_ids(
+        &self,
+        ws_id: u64,
+        ids: &[i64],
+    ) -> Result<Vec<ChatUser>, AppError> {
+        let users = query_as("SELECT u.id, u.fullname, u.email FROM workspace_members m JOIN users u ON u.id = m.user_id WHERE m.ws_id = $1 AND u.id = ANY($2)")
+            .bind(ws_id as i64)
             .bind(ids)
             .fetch_all(&self.pool)
             .await?;
@@ -121,7 +169,7 @@ impl AppState {
 
     #[allow(dead_code)]
     pub async fn fetch_chat_user_all(&self, ws_id: u64) -> Result<Vec<ChatUser>, AppError> {
-        let users = query_as("SELECT id, fullname, email FROM users WHERE ws_id = $1")
+        let users = query_as("SELECT u.id, u.fullname, u.email FROM workspace_members m JOIN users u ON u.id = m.user_id WHERE m.ws_id = $1 ORDER BY u.id")
             .bind(ws_id as i64)
             .fetch_all(&self.pool)
             .await?;
@@ -164,8 +212,14 @@ impl CreateUser {
             workspace: ws.to_string(),
             email: email.to_string(),
             password: password.to_string(),
+            invite_code: None,
         }
     }
+
+    pub fn with_invite(mut self, code: &str) -> Self {
+        self.invite_code = Some(code.to_string());
+        self
+    }
 }
 
 #[cfg(test)]
@@ -196,7 +250,7 @@ mod tests {
     #[tokio::test]
     async fn create_already_exists_user_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("none", "nyh@qq.com", "张三", "zhangsan123");
+        let input = CreateUser::new("zhangsan", "nyh@qq.com", "张三", "zhangsan123");
         state.create_user(&input).await?;
         let ret = state.create_user(&input).await;
         match ret {
@@ -212,7 +266,7 @@ mod tests {
     #[tokio::test]
     async fn create_and_verify_user_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("none", "nyh@qq.com", "张三", "zhangsan123");
+        let input = CreateUser::new("zhangsan", "nyh@qq.com", "张三", "zhangsan123");
         let user = state.create_user(&input).await?;
         assert_eq!(user.email, input.email);
         assert_eq!(user.fullname, input.fullname);
@@ -231,6 +285,16 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn create_user_in_existing_workspace_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("acme", "tom", "tom@acme.org", "tom123");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::WorkspaceAlreadyExists(_))));
+        assert!(state.find_user_by_email("tom@acme.org").await?.is_none());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn find_user_by_id_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -0,0 +1,220 @@
+use super::{generate_secret, hash_secret};
+use crate::{mailer::Mail, AppError, AppState};
+use chat_core::User;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_scalar};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "user_token_purpose", rename_all = "snake_case")]
+pub enum TokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+impl TokenPurpose {
+    fn ttl(&self) -> Duration {
+        match self {
+            TokenPurpose::VerifyEmail => Duration::hours(24),
+            TokenPurpose::ResetPassword => Duration::hours(1),
+        }
+    }
+}
+
+impl AppState {
+    /// Issue a single use token, older unused tokens for the same purpose stop working
+    pub async fn create_user_token(
+        &self,
+        user_id: u64,
+        purpose: TokenPurpose,
+    ) -> Result<String, AppError> {
+        let secret = generate_secret();
+        let mut tx = self.pool.begin().await?;
+        query("UPDATE user_tokens SET used_at = NOW() WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL")
+            .bind(user_id as i64)
+            .bind(purpose)
+            .execute(&mut *tx)
+            .await?;
+        query("INSERT INTO user_tokens (user_id, purpose, token_hash, expires_at) VALUES ($1, $2, $3, $4)")
+            .bind(user_id as i64)
+            .bind(purpose)
+            .bind(hash_secret(&secret))
+            .bind(Utc::now() + purpose.ttl())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(secret)
+    }
+
+    /// Use up the token, return the user it was issued to
+    pub async fn consume_user_token(
+        &self,
+        token: &str,
+        purpose: TokenPurpose,
+    ) -> Result<u64, AppError> {
+        let user_id: Option<i64> = query_scalar(
+            "UPDATE user_tokens SET used_at = NOW() WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW() RETURNING user_id",
+        )
+        .bind(hash_secret(token))
+        .bind(purpose)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match user_id {
+            Some(id) => Ok(id as _),
+            None => Err(AppError::InvalidUserToken),
+        }
+    }
+
+    pub async fn send_verification_email(&self, user: &User) -> Result<(), AppError> {
+        let token = self
+            .create_user_token(user.id as _, TokenPurpose::VerifyEmail)
+            .await?;
+        let link = format!(
+            "{}/verify-email?token={}",
+            self.config.mailer.app_url, token
+        );
+        let body = format!(
+            "Hi {},\n\nPlease confirm your email address by opening the link below:\n\n{}\n\nThe link expires in 24 hours.\n",
+            user.fullname, link
+        );
+        let mail = Mail::new(&user.email, "Verify your email address", body);
+        self.mailer.send(&mail).await
+    }
+
+    pub async fn verify_email(&self, input: &VerifyEmail) -> Result<(), AppError> {
+        let user_id = self
+            .consume_user_token(&input.token, TokenPurpose::VerifyEmail)
+            .await?;
+        self.mark_email_verified(user_id).await
+    }
+
+    /// Mail a password reset link, unknown emails are silently ignored so they can't be probed
+    pub async fn send_password_reset_email(&self, input: &ForgotPassword) -> Result<(), AppError> {
+        let Some(user) = self.find_user_by_email(&input.email).await? else {
+            return Ok(());
+        };
+        let token = self
+            .create_user_token(user.id as _, TokenPurpose::ResetPassword)
+            .await?;
+        let link = format!(
+            "{}/reset-password?token={}",
+            self.config.mailer.app_url, token
+        );
+        let body = format!(
+            "Hi {},\n\nSomeone asked to reset the password of your account. If it was you, open the link below to choose a new one:\n\n{}\n\nThe link expires in 1 hour, ignore this mail otherwise.\n",
+            user.fullname, link
+        );
+        let mail = Mail::new(&user.email, "Reset your password", body);
+        self.mailer.send(&mail).await
+    }
+
+    /// Set a new password and sign the user out everywhere
+    pub async fn reset_password(&self, input: &ResetPassword) -> Result<(), AppError> {
+        let user_id = self
+            .consume_user_token(&input.token, TokenPurpose::ResetPassword)
+            .await?;
+        self.update_password(user_id, &input.password).await?;
+        // receiving the mail proves the address is theirs
+        self.mark_email_verified(user_id).await?;
+        self.revoke_user_sessions(user_id).await
+    }
+}
+
+#[cfg(test)]
+impl ResetPassword {
+    pub fn new(token: &str, password: &str) -> Self {
+        Self {
+            token: token.to_string(),
+            password: password.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SigninUser;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn user_token_should_be_single_use() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token = state
+            .create_user_token(1, TokenPurpose::VerifyEmail)
+            .await?;
+
+        // purposes don't mix
+        let ret = state
+            .consume_user_token(&token, TokenPurpose::ResetPassword)
+            .await;
+        assert!(matches!(ret, Err(AppError::InvalidUserToken)));
+
+        let user_id = state
+            .consume_user_token(&token, TokenPurpose::VerifyEmail)
+            .await?;
+        assert_eq!(user_id, 1);
+
+        let ret = state
+            .consume_user_token(&token, TokenPurpose::VerifyEmail)
+            .await;
+        assert!(matches!(ret, Err(AppError::InvalidUserToken)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn new_user_token_should_invalidate_older_ones() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token1 = state
+            .create_user_token(1, TokenPurpose::ResetPassword)
+            .await?;
+        let token2 = state
+            .create_user_token(1, TokenPurpose::ResetPassword)
+            .await?;
+
+        let ret = state
+            .consume_user_token(&token1, TokenPurpose::ResetPassword)
+            .await;
+        assert!(matches!(ret, Err(AppError::InvalidUserToken)));
+        state
+            .consume_user_token(&token2, TokenPurpose::ResetPassword)
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reset_password_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (session, _) = state.create_session(1, 1).await?;
+        let token = state
+            .create_user_token(1, TokenPurpose::ResetPassword)
+            .await?;
+        state
+            .reset_password(&ResetPassword::new(&token, "new-password"))
+            .await?;
+
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = SigninUser::new(&user.email, "new-password");
+        assert!(state.verify_user(&input).await?.is_some());
+        assert!(!state.is_session_active(session.id as _).await?);
+        Ok(())
+    }
+}
@@ -1,15 +1,39 @@
 use crate::{AppError, AppState};
-use chat_core::Workspace;
-use sqlx::query_as;
+use chat_core::{Workspace, WorkspaceRole};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, FromRow, PgConnection};
+use utoipa::ToSchema;
+
+/// A workspace the user belongs to, along with their role in it
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
+pub struct UserWorkspace {
+    pub id: i64,
+    pub name: String,
+    pub owner_id: i64,
+    pub role: WorkspaceRole,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UpdateWorkspace {
+    pub name: String,
+}
 
 impl AppState {
-    pub async fn create_workspace(&self, name: &str, user_id: u16) -> Result<Workspace, AppError> {
+    /// Run inside the signup transaction, the owner is set once the user exists
+    pub async fn create_workspace(
+        &self,
+        conn: &mut PgConnection,
+        name: &str,
+        user_id: u16,
+    ) -> Result<Workspace, AppError> {
         let ws = query_as(
         "INSERT INTO workspaces (name, owner_id) VALUES ($1, $2) RETURNING id, name, owner_id, created_at"
       )
       .bind(name)
       .bind(user_id as i64)
-      .fetch_one(&self.pool)
+      .fetch_one(conn)
       .await?;
 
         Ok(ws)
@@ -36,17 +60,87 @@ impl AppState {
         Ok(ws)
     }
 
+    pub async fn update_workspace(
+        &self,
+        id: u64,
+        input: &UpdateWorkspace,
+    ) -> Result<Workspace, AppError> {
+        if input.name.len() < 3 {
+            return Err(AppError::UpdateWorkspaceError(
+                "workspace name must have at least 3 characters".to_string(),
+            ));
+        }
+        if self.find_workspace_by_name(&input.name).await?.is_some() {
+            return Err(AppError::WorkspaceAlreadyExists(input.name.clone()));
+        }
+
+        let ws = query_as(
+            "UPDATE workspaces SET name = $1 WHERE id = $2 RETURNING id, name, owner_id, created_at",
+        )
+        .bind(&input.name)
+        .bind(id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(ws)
+    }
+
+    pub async fn fetch_user_workspaces(
+        &self,
+        user_id: u64,
+    ) -> Result<Vec<UserWorkspace>, AppError> {
+        let workspaces = query_as(
+            "SELECT w.id, w.name, w.owner_id, m.role, w.created_at FROM workspace_members m JOIN workspaces w ON w.id = m.ws_id WHERE m.user_id = $1 ORDER BY w.id",
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(workspaces)
+    }
+
+    /// A workspace the user is a member of, nothing is changed
+    pub async fn find_member_workspace(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<Workspace, AppError> {
+        let Some(ws) = self.find_workspace_by_id(ws_id).await? else {
+            return Err(AppError::NotFound(format!("workspace id {}", ws_id)));
+        };
+        if self.get_member_role(ws_id, user_id).await?.is_none() {
+            return Err(AppError::PermissionDenied(format!(
+                "user {} is not a member of workspace {}",
+                user_id, ws_id
+            )));
+        }
+
+        Ok(ws)
+    }
+
+    /// Make the workspace the one the user signs in to next time, open sessions keep theirs
+    pub async fn set_default_workspace(&self, user_id: u64, ws_id: u64) -> Result<(), AppError> {
+        query("UPDATE users SET ws_id = $1 WHERE id = $2")
+            .bind(ws_id as i64)
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn update_workspace_owner(
         &self,
+        conn: &mut PgConnection,
         ws_id: u64,
         owner_id: u64,
     ) -> Result<Workspace, AppError> {
         let ws = query_as(
-            "UPDATE workspaces SET owner_id = $1 WHERE id = $2 and (SELECT ws_id FROM users WHERE id = $1) = $2 RETURNING id, name, owner_id, created_at"
+            "UPDATE workspaces SET owner_id = $1 WHERE id = $2 AND EXISTS (SELECT 1 FROM workspace_members WHERE ws_id = $2 AND user_id = $1) RETURNING id, name, owner_id, created_at"
           )
           .bind(owner_id as i64)
           .bind(ws_id as i64)
-          .fetch_one(&self.pool)
+          .fetch_one(conn)
           .await?;
 
         Ok(ws)
@@ -73,6 +167,34 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn failed_signup_should_not_leave_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // the user insert fails after the workspace was created
+        let fullname = "a".repeat(65);
+        let input = CreateUser::new("test", &fullname, "alice1@gmail.com", "alice123");
+        assert!(state.create_user(&input).await.is_err());
+        assert!(state.find_workspace_by_name("test").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_should_update_name() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateWorkspace {
+            name: "acme2".to_string(),
+        };
+        let ws = state.update_workspace(1, &input).await?;
+        assert_eq!(ws.name, "acme2");
+
+        let input = UpdateWorkspace {
+            name: "foo".to_string(),
+        };
+        let ret = state.update_workspace(1, &input).await;
+        assert!(matches!(ret, Err(AppError::WorkspaceAlreadyExists(_))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn workspace_should_find_by_name() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
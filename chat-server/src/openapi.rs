@@ -1,10 +1,19 @@
 use crate::{
     handlers::*,
-    models::{CreateMessage, CreateUser, ListMessage, ParamChat, SigninUser},
+    models::{
+        AcceptInvite, ChannelSummary, ChatMembers, CreateDirectChat, CreateInvite, CreateMessage,
+        CreateReaction, CreateUser, ForgotPassword, InviteOutput, ListChannels, ListChats,
+        ListMessage, MarkRead, MessageRevision, ParamChat, RefreshToken, ResetPassword, SigninUser,
+        UpdateChatInfo, UpdateMessage, UpdateRole, UpdateWorkspace, UserWorkspace, VerifyEmail,
+        WorkspaceInvite,
+    },
     AppState, AuthOutput, ErrorOutput,
 };
 use axum::Router;
-use chat_core::{Chat, ChatType, ChatUser, Message, User, Workspace};
+use chat_core::{
+    Chat, ChatRead, ChatType, ChatUser, Jwk, Jwks, Mention, MentionKind, Message, Reaction,
+    ReactionCount, User, Workspace, WorkspaceRole,
+};
 use utoipa::{
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
     Modify, OpenApi,
@@ -22,20 +31,49 @@ pub(crate) trait OpenApiRouter {
         paths(
             signup_handler,
             signin_handler,
+            refresh_handler,
+            signout_handler,
+            switch_workspace_handler,
+            verify_email_handler,
+            resend_verification_handler,
+            forgot_password_handler,
+            reset_password_handler,
+            jwks_handler,
             list_chat_handler,
+            list_channels_handler,
+            join_channel_handler,
             get_chat_handler,
             create_chat_handler,
-            update_chat_handler,
+            direct_chat_handler,
+            update_chat_info_handler,
             delete_chat_handler,
+            add_chat_members_handler,
+            remove_chat_members_handler,
+            leave_chat_handler,
+            mark_chat_read_handler,
+            list_chat_reads_handler,
             upload_handler,
             file_handler,
             list_message_handler,
             delete_message_handler,
             send_message_handler,
+            update_message_handler,
+            list_message_history_handler,
+            list_thread_handler,
+            list_reactions_handler,
+            add_reaction_handler,
+            remove_reaction_handler,
             list_chat_user_handler,
+            list_workspace_handler,
+            join_workspace_handler,
+            list_invite_handler,
+            create_invite_handler,
+            revoke_invite_handler,
+            update_role_handler,
+            update_workspace_handler,
         ),
         components(
-            schemas(User, Chat, ChatType, ChatUser, Message, Workspace, SigninUser, CreateUser, CreateMessage, ListMessage, AuthOutput, ErrorOutput, ParamChat),
+            schemas(User, Chat, ChatType, ChatUser, Message, Workspace, SigninUser, CreateUser, CreateMessage, ListMessage, AuthOutput, ErrorOutput, ParamChat, RefreshToken, Jwks, Jwk, VerifyEmail, ForgotPassword, ResetPassword, CreateInvite, InviteOutput, WorkspaceInvite, WorkspaceRole, UpdateRole, UpdateWorkspace, UserWorkspace, AcceptInvite, UpdateMessage, MessageRevision, CreateReaction, Reaction, ReactionCount, Mention, MentionKind, MarkRead, ChatRead, ChatMembers, ListChannels, ChannelSummary, CreateDirectChat, ListChats, UpdateChatInfo),
         ),
         modifiers(&SecurityAddon),
         tags(
@@ -0,0 +1,17 @@
+-- Add migration script here
+-- login sessions, each one holds the hash of its current refresh token
+CREATE TABLE IF NOT EXISTS sessions(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  -- sha256 of the refresh token, hex encoded
+  token_hash char(64) NOT NULL,
+  -- hash of the refresh token it replaced, seeing it again means the token was stolen
+  prev_token_hash char(64),
+  expires_at timestamptz NOT NULL,
+  revoked_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  updated_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for sessions for user_id
+CREATE INDEX IF NOT EXISTS sessions_user_id_index ON sessions(user_id);
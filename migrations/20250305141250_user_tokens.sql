@@ -0,0 +1,24 @@
+-- Add migration script here
+ALTER TABLE users
+  ADD COLUMN email_verified_at timestamptz;
+
+-- create user token purpose: verify_email, reset_password
+CREATE TYPE user_token_purpose AS ENUM(
+  'verify_email',
+  'reset_password'
+);
+
+-- single use tokens mailed to users
+CREATE TABLE IF NOT EXISTS user_tokens(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  purpose user_token_purpose NOT NULL,
+  -- sha256 of the token, hex encoded
+  token_hash char(64) NOT NULL UNIQUE,
+  expires_at timestamptz NOT NULL,
+  used_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for user tokens for user_id and purpose
+CREATE INDEX IF NOT EXISTS user_tokens_user_id_purpose_index ON user_tokens(user_id, purpose);
@@ -0,0 +1,20 @@
+-- Add migration script here
+-- invitations to join a workspace
+CREATE TABLE IF NOT EXISTS workspace_invites(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  inviter_id bigint NOT NULL REFERENCES users(id),
+  -- only this email may accept the invite, NULL for shareable links
+  email varchar(64),
+  -- sha256 of the invite code, hex encoded
+  code_hash char(64) NOT NULL UNIQUE,
+  -- NULL for unlimited
+  max_uses int,
+  use_count int NOT NULL DEFAULT 0,
+  expires_at timestamptz NOT NULL,
+  revoked_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for workspace invites for ws_id
+CREATE INDEX IF NOT EXISTS workspace_invites_ws_id_index ON workspace_invites(ws_id, created_at DESC);
@@ -0,0 +1,37 @@
+-- Add migration script here
+-- create workspace role: owner, admin, member, guest
+CREATE TYPE workspace_role AS ENUM(
+  'owner',
+  'admin',
+  'member',
+  'guest'
+);
+
+-- role of a user in a workspace
+CREATE TABLE IF NOT EXISTS workspace_members(
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  user_id bigint NOT NULL REFERENCES users(id),
+  role workspace_role NOT NULL DEFAULT 'member',
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (ws_id, user_id)
+);
+
+-- existing users become members of their workspace, owners keep owning it
+INSERT INTO workspace_members(ws_id, user_id, role)
+SELECT
+  u.ws_id,
+  u.id,
+  CASE WHEN w.owner_id = u.id THEN
+    'owner'::workspace_role
+  ELSE
+    'member'::workspace_role
+  END
+FROM
+  users u
+  JOIN workspaces w ON w.id = u.ws_id
+WHERE
+  u.id <> 0;
+
+-- role given to people joining with the invite
+ALTER TABLE workspace_invites
+  ADD COLUMN role workspace_role NOT NULL DEFAULT 'member';
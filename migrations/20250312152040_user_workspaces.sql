@@ -0,0 +1,7 @@
+-- Add migration script here
+-- users may belong to several workspaces through workspace_members,
+-- users.ws_id only remembers the one to sign in to
+COMMENT ON COLUMN users.ws_id IS 'default workspace, the last one switched to';
+
+-- create index for workspace members for user_id
+CREATE INDEX IF NOT EXISTS workspace_members_user_id_index ON workspace_members(user_id);
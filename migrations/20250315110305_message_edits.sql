@@ -0,0 +1,52 @@
+-- Add migration script here
+ALTER TABLE messages
+  ADD COLUMN edited_at timestamptz;
+
+-- prior revisions of edited messages
+CREATE TABLE IF NOT EXISTS message_history(
+  id bigserial PRIMARY KEY,
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  content text NOT NULL,
+  files text[] DEFAULT '{}',
+  -- when the revision was replaced
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for message history for message_id
+CREATE INDEX IF NOT EXISTS message_history_message_id_index ON message_history(message_id, id);
+
+-- notify chat members about new and edited messages
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' OR (TG_OP = 'UPDATE' AND OLD.content IS DISTINCT FROM NEW.content) THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    IF TG_OP = 'INSERT' THEN
+      PERFORM
+        pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS)::text);
+    ELSE
+      PERFORM
+        pg_notify('chat_message_updated', json_build_object('message', NEW, 'members', USERS)::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS add_to_message_trigger ON messages;
+
+CREATE TRIGGER add_to_message_trigger
+  AFTER INSERT OR UPDATE ON messages
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_message();
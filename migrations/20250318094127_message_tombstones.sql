@@ -0,0 +1,41 @@
+-- Add migration script here
+-- deleted messages stay as tombstones, their content is never served again
+ALTER TABLE messages
+  ADD COLUMN deleted_at timestamptz,
+  ADD COLUMN deleted_by bigint REFERENCES users(id);
+
+-- notify chat members about new, edited and deleted messages
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  tombstone messages;
+BEGIN
+  IF TG_OP = 'INSERT' OR (TG_OP = 'UPDATE' AND (OLD.content IS DISTINCT FROM NEW.content OR OLD.deleted_at IS DISTINCT FROM NEW.deleted_at)) THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    IF TG_OP = 'INSERT' THEN
+      PERFORM
+        pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS)::text);
+    ELSIF NEW.deleted_at IS NOT NULL THEN
+      tombstone := NEW;
+      tombstone.content := '';
+      tombstone.files := '{}';
+      PERFORM
+        pg_notify('chat_message_deleted', json_build_object('message', tombstone, 'members', USERS)::text);
+    ELSE
+      PERFORM
+        pg_notify('chat_message_updated', json_build_object('message', NEW, 'members', USERS)::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,58 @@
+-- Add migration script here
+-- replies point to the top level message starting the thread
+ALTER TABLE messages
+  ADD COLUMN parent_id bigint REFERENCES messages(id),
+  ADD COLUMN reply_count int NOT NULL DEFAULT 0,
+  ADD COLUMN last_reply_at timestamptz;
+
+-- create index for messages for parent_id
+CREATE INDEX IF NOT EXISTS parent_id_index ON messages(parent_id, id)
+WHERE
+  parent_id IS NOT NULL;
+
+-- notify chat members about new, edited and deleted messages and thread replies
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  tombstone messages;
+  parent messages;
+BEGIN
+  IF TG_OP = 'INSERT' OR (TG_OP = 'UPDATE' AND (OLD.content IS DISTINCT FROM NEW.content OR OLD.deleted_at IS DISTINCT FROM NEW.deleted_at)) THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    IF TG_OP = 'INSERT' AND NEW.parent_id IS NOT NULL THEN
+      -- the parent carries the updated reply count for thread badges
+      SELECT
+        * INTO parent
+      FROM
+        messages
+      WHERE
+        id = NEW.parent_id;
+      PERFORM
+        pg_notify('chat_thread_replied', json_build_object('message', NEW, 'parent', parent, 'members', USERS)::text);
+    ELSIF TG_OP = 'INSERT' THEN
+      PERFORM
+        pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS)::text);
+    ELSIF NEW.deleted_at IS NOT NULL THEN
+      tombstone := NEW;
+      tombstone.content := '';
+      tombstone.files := '{}';
+      PERFORM
+        pg_notify('chat_message_deleted', json_build_object('message', tombstone, 'members', USERS)::text);
+    ELSE
+      PERFORM
+        pg_notify('chat_message_updated', json_build_object('message', NEW, 'members', USERS)::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,50 @@
+-- Add migration script here
+-- one row per user and emoji on a message, emoji is a shortcode like :+1: or the unicode itself
+CREATE TABLE IF NOT EXISTS message_reactions(
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  emoji varchar(64) NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  PRIMARY KEY (message_id, user_id, emoji)
+);
+
+-- notify chat members when a reaction is added or removed
+CREATE OR REPLACE FUNCTION reaction_changed()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  reaction message_reactions;
+  chat bigint;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    reaction := NEW;
+  ELSE
+    reaction := OLD;
+  END IF;
+  RAISE NOTICE 'reaction_changed: %', reaction;
+  SELECT
+    c.id,
+    c.members INTO chat,
+    USERS
+  FROM
+    messages m
+    JOIN chats c ON c.id = m.chat_id
+  WHERE
+    m.id = reaction.message_id;
+  IF TG_OP = 'INSERT' THEN
+    PERFORM
+      pg_notify('chat_reaction_added', json_build_object('reaction', reaction, 'chat_id', chat, 'members', USERS)::text);
+  ELSE
+    PERFORM
+      pg_notify('chat_reaction_removed', json_build_object('reaction', reaction, 'chat_id', chat, 'members', USERS)::text);
+  END IF;
+  RETURN reaction;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER reaction_changed_trigger
+  AFTER INSERT OR DELETE ON message_reactions
+  FOR EACH ROW
+  EXECUTE FUNCTION reaction_changed();
@@ -0,0 +1,89 @@
+-- Add migration script here
+-- @user mentions carry the user, @channel and @here address everyone in the chat
+CREATE TYPE mention_kind AS ENUM(
+  'user',
+  'channel',
+  'here'
+);
+
+CREATE TABLE IF NOT EXISTS message_mentions(
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  kind mention_kind NOT NULL,
+  user_id bigint REFERENCES users(id),
+  UNIQUE NULLS NOT DISTINCT (message_id, kind, user_id)
+);
+
+-- create index for message_mentions for user_id
+CREATE INDEX IF NOT EXISTS message_mentions_user_id_index ON message_mentions(user_id)
+WHERE
+  user_id IS NOT NULL;
+
+-- mentions of a message are inserted together, notify everyone mentioned once per message
+CREATE OR REPLACE FUNCTION message_mentioned()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  msg messages;
+  mentions json;
+BEGIN
+  FOR msg IN
+  SELECT
+    *
+  FROM
+    messages
+  WHERE
+    id IN (
+      SELECT
+        message_id
+      FROM
+        inserted)
+      LOOP
+        RAISE NOTICE 'message_mentioned: %', msg;
+        SELECT
+          json_agg(json_build_object('kind', kind, 'user_id', user_id)) INTO mentions
+        FROM
+          inserted
+        WHERE
+          message_id = msg.id;
+        SELECT
+          ARRAY (
+            SELECT DISTINCT
+              u
+            FROM (
+              SELECT
+                i.user_id AS u
+              FROM
+                inserted i
+              WHERE
+                i.message_id = msg.id
+                AND i.kind = 'user'
+              UNION
+              SELECT
+                unnest(c.members) AS u
+              FROM
+                chats c
+              WHERE
+                c.id = msg.chat_id
+                AND EXISTS (
+                  SELECT
+                    1
+                  FROM
+                    inserted i
+                  WHERE
+                    i.message_id = msg.id
+                    AND i.kind <> 'user')) t
+            WHERE
+              u <> msg.sender_id) INTO USERS;
+        PERFORM
+          pg_notify('chat_message_mentioned', json_build_object('message', msg, 'mentions', mentions, 'members', USERS)::text);
+      END LOOP;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER message_mentioned_trigger
+  AFTER INSERT ON message_mentions REFERENCING NEW TABLE AS inserted
+  FOR EACH STATEMENT
+  EXECUTE FUNCTION message_mentioned();
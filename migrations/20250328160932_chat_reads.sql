@@ -0,0 +1,32 @@
+-- Add migration script here
+-- read position of each member in a chat, messages after last_read_id are unread
+CREATE TABLE IF NOT EXISTS chat_reads(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  last_read_id bigint NOT NULL,
+  updated_at timestamptz DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  PRIMARY KEY (chat_id, user_id)
+);
+
+-- create index for messages for counting unread ones
+CREATE INDEX IF NOT EXISTS chat_id_id_index ON messages(chat_id, id);
+
+-- sync the read position to the other devices of the reader
+CREATE OR REPLACE FUNCTION chat_read_updated()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'INSERT' OR OLD.last_read_id <> NEW.last_read_id THEN
+    RAISE NOTICE 'chat_read_updated: %', NEW;
+    PERFORM
+      pg_notify('chat_read_updated', json_build_object('read', NEW, 'members', ARRAY[NEW.user_id])::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER chat_read_updated_trigger
+  AFTER INSERT OR UPDATE ON chat_reads
+  FOR EACH ROW
+  EXECUTE FUNCTION chat_read_updated();
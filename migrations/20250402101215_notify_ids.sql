@@ -0,0 +1,135 @@
+-- Add migration script here
+-- pg_notify payloads are limited to 8000 bytes, so triggers only send ids and
+-- notify-server loads the rows itself
+
+-- the chat before an update or delete, kept for a while so the change can be diffed
+CREATE TABLE IF NOT EXISTS chat_changes(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL,
+  ws_id bigint NOT NULL,
+  name varchar(64),
+  type chat_type NOT NULL,
+  members bigint[] NOT NULL,
+  created_at timestamptz,
+  changed_at timestamptz DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+-- create index for chat_changes for pruning
+CREATE INDEX IF NOT EXISTS chat_changes_changed_at_index ON chat_changes(changed_at);
+
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  change_id bigint;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_chat: %', NEW.id;
+    PERFORM
+      pg_notify('chat_updated', json_build_object('op', TG_OP, 'id', NEW.id)::text);
+    RETURN NEW;
+  END IF;
+  RAISE NOTICE 'add_to_chat: %', OLD.id;
+  -- every replica reads the change when notified, so it can't be removed once read
+  DELETE FROM chat_changes
+  WHERE changed_at < CURRENT_TIMESTAMP - interval '1 hour';
+  INSERT INTO chat_changes(chat_id, ws_id, name, type, members, created_at)
+    VALUES (OLD.id, OLD.ws_id, OLD.name, OLD.type, OLD.members, OLD.created_at)
+  RETURNING
+    id INTO change_id;
+  PERFORM
+    pg_notify('chat_updated', json_build_object('op', TG_OP, 'id', OLD.id, 'change_id', change_id)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'INSERT' OR (TG_OP = 'UPDATE' AND (OLD.content IS DISTINCT FROM NEW.content OR OLD.deleted_at IS DISTINCT FROM NEW.deleted_at)) THEN
+    RAISE NOTICE 'add_to_message: %', NEW.id;
+    IF TG_OP = 'INSERT' AND NEW.parent_id IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_thread_replied', json_build_object('id', NEW.id, 'parent_id', NEW.parent_id)::text);
+    ELSIF TG_OP = 'INSERT' THEN
+      PERFORM
+        pg_notify('chat_message_created', json_build_object('id', NEW.id)::text);
+    ELSIF NEW.deleted_at IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_deleted', json_build_object('id', NEW.id)::text);
+    ELSE
+      PERFORM
+        pg_notify('chat_message_updated', json_build_object('id', NEW.id)::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+-- a removed reaction is gone when notify-server gets to it, so the small row itself is sent
+CREATE OR REPLACE FUNCTION reaction_changed()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  reaction message_reactions;
+  chat bigint;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    reaction := NEW;
+  ELSE
+    reaction := OLD;
+  END IF;
+  RAISE NOTICE 'reaction_changed: %', reaction;
+  SELECT
+    chat_id INTO chat
+  FROM
+    messages
+  WHERE
+    id = reaction.message_id;
+  IF TG_OP = 'INSERT' THEN
+    PERFORM
+      pg_notify('chat_reaction_added', json_build_object('reaction', reaction, 'chat_id', chat)::text);
+  ELSE
+    PERFORM
+      pg_notify('chat_reaction_removed', json_build_object('reaction', reaction, 'chat_id', chat)::text);
+  END IF;
+  RETURN reaction;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION message_mentioned()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  msg_id bigint;
+BEGIN
+  FOR msg_id IN SELECT DISTINCT
+    message_id
+  FROM
+    inserted LOOP
+      RAISE NOTICE 'message_mentioned: %', msg_id;
+      PERFORM
+        pg_notify('chat_message_mentioned', json_build_object('id', msg_id)::text);
+    END LOOP;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION chat_read_updated()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'INSERT' OR OLD.last_read_id <> NEW.last_read_id THEN
+    RAISE NOTICE 'chat_read_updated: %', NEW;
+    PERFORM
+      pg_notify('chat_read_updated', json_build_object('read', NEW)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
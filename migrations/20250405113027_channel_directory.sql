@@ -0,0 +1,9 @@
+-- Add migration script here
+-- what a channel is about, shown in the channel directory
+ALTER TABLE chats
+  ADD COLUMN description varchar(256);
+
+-- create index for chats for browsing public channels
+CREATE INDEX IF NOT EXISTS chats_public_ws_id_index ON chats(ws_id, name)
+WHERE
+  type = 'public_channel';
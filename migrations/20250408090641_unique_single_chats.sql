@@ -0,0 +1,32 @@
+-- Add migration script here
+-- a single chat between two people exists once per workspace, whatever the order of members
+
+-- merge the duplicates created so far into the oldest one
+CREATE TEMPORARY TABLE single_chat_duplicates AS
+SELECT
+  id,
+  first_value(id) OVER (PARTITION BY ws_id, LEAST(members[1], members[2]), GREATEST(members[1], members[2]) ORDER BY id) AS keep_id
+FROM
+  chats
+WHERE
+  type = 'single';
+
+UPDATE
+  messages m
+SET
+  chat_id = d.keep_id
+FROM
+  single_chat_duplicates d
+WHERE
+  m.chat_id = d.id
+  AND d.id <> d.keep_id;
+
+DELETE FROM chats c USING single_chat_duplicates d
+WHERE c.id = d.id
+  AND d.id <> d.keep_id;
+
+DROP TABLE single_chat_duplicates;
+
+CREATE UNIQUE INDEX IF NOT EXISTS chats_single_members_index ON chats(ws_id, LEAST(members[1], members[2]), GREATEST(members[1], members[2]))
+WHERE
+  type = 'single';
@@ -0,0 +1,39 @@
+-- Add migration script here
+-- what a chat is about right now and how it looks, archived chats are read-only
+ALTER TABLE chats
+  ADD COLUMN topic varchar(256),
+  ADD COLUMN icon varchar(256),
+  ADD COLUMN archived_at timestamptz;
+
+ALTER TABLE chat_changes
+  ADD COLUMN topic varchar(256),
+  ADD COLUMN description varchar(256),
+  ADD COLUMN icon varchar(256),
+  ADD COLUMN archived_at timestamptz;
+
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  change_id bigint;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_chat: %', NEW.id;
+    PERFORM
+      pg_notify('chat_updated', json_build_object('op', TG_OP, 'id', NEW.id)::text);
+    RETURN NEW;
+  END IF;
+  RAISE NOTICE 'add_to_chat: %', OLD.id;
+  -- every replica reads the change when notified, so it can't be removed once read
+  DELETE FROM chat_changes
+  WHERE changed_at < CURRENT_TIMESTAMP - interval '1 hour';
+  INSERT INTO chat_changes(chat_id, ws_id, name, type, members, topic, description, icon, archived_at, created_at)
+    VALUES (OLD.id, OLD.ws_id, OLD.name, OLD.type, OLD.members, OLD.topic, OLD.description, OLD.icon, OLD.archived_at, OLD.created_at)
+  RETURNING
+    id INTO change_id;
+  PERFORM
+    pg_notify('chat_updated', json_build_object('op', TG_OP, 'id', OLD.id, 'change_id', change_id)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
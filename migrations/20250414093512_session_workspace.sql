@@ -0,0 +1,19 @@
+-- Add migration script here
+-- every session works in its own workspace, switching in one doesn't move the others
+ALTER TABLE sessions
+  ADD COLUMN IF NOT EXISTS ws_id bigint REFERENCES workspaces(id);
+
+UPDATE
+  sessions s
+SET
+  ws_id = u.ws_id
+FROM
+  users u
+WHERE
+  u.id = s.user_id
+  AND s.ws_id IS NULL;
+
+ALTER TABLE sessions
+  ALTER COLUMN ws_id SET NOT NULL;
+
+COMMENT ON COLUMN users.ws_id IS 'default workspace of new sessions, the last one switched to';
@@ -0,0 +1,25 @@
+-- Add migration script here
+-- the notification carries the mentions just inserted, an edit only notifies the people it adds
+CREATE OR REPLACE FUNCTION message_mentioned()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  msg_id bigint;
+  added json;
+BEGIN
+  FOR msg_id,
+  added IN SELECT
+    message_id,
+    json_agg(json_build_object('kind', kind, 'user_id', user_id))
+  FROM
+    inserted
+  GROUP BY
+    message_id LOOP
+      RAISE NOTICE 'message_mentioned: %', msg_id;
+      PERFORM
+        pg_notify('chat_message_mentioned', json_build_object('id', msg_id, 'mentions', added)::text);
+    END LOOP;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
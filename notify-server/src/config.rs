@@ -7,17 +7,110 @@ use serde::{Deserialize, Serialize};
 pub struct NotifyConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub presence: PresenceConfig,
+    #[serde(default)]
+    pub fanout: FanOutConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
+    /// recent events kept per user for clients resuming with Last-Event-ID
+    #[serde(default = "default_event_log_capacity")]
+    pub event_log_capacity: usize,
+    /// how long the events of a user are kept after their last stream closed
+    #[serde(default = "default_event_log_retention_secs")]
+    pub event_log_retention_secs: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthConfig {
-    pub pk: String,
+    /// public key to verify tokens with when jwks_url is not set or can't be fetched on startup
+    #[serde(default)]
+    pub pk: Option<String>,
+    /// chat-server's key set, e.g. http://localhost:6688/.well-known/jwks.json
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    /// how often the key set is fetched again
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PresenceConfig {
+    /// connected users without activity for this long are shown as away
+    #[serde(default = "default_away_after_secs")]
+    pub away_after_secs: u64,
+    /// users offline for this long are no longer listed
+    #[serde(default = "default_forget_offline_after_secs")]
+    pub forget_offline_after_secs: u64,
+    /// users of a replica not heard from for this long are taken offline,
+    /// replicas send a heartbeat three times as often
+    #[serde(default = "default_replica_ttl_secs")]
+    pub replica_ttl_secs: u64,
+    /// how long a typing signal is shown without being repeated
+    #[serde(default = "default_typing_ttl_secs")]
+    pub typing_ttl_secs: u64,
+}
+
+/// Event ids are handed out by each replica, so clients resuming with Last-Event-ID
+/// need to reconnect to the same replica or they are asked to resync
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FanOutConfig {
+    /// how typing and presence signals reach users connected to other replicas
+    #[serde(default)]
+    pub backend: FanOutBackend,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FanOutBackend {
+    /// a single replica
+    #[default]
+    InProcess,
+    /// replicas sharing the database, through pg_notify
+    Postgres,
+}
+
+fn default_event_log_capacity() -> usize {
+    256
+}
+
+fn default_event_log_retention_secs() -> u64 {
+    120
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    300
+}
+
+fn default_away_after_secs() -> u64 {
+    300
+}
+
+fn default_forget_offline_after_secs() -> u64 {
+    3600
+}
+
+fn default_replica_ttl_secs() -> u64 {
+    30
+}
+
+fn default_typing_ttl_secs() -> u64 {
+    6
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            away_after_secs: default_away_after_secs(),
+            forget_offline_after_secs: default_forget_offline_after_secs(),
+            replica_ttl_secs: default_replica_ttl_secs(),
+            typing_ttl_secs: default_typing_ttl_secs(),
+        }
+    }
 }
 
 impl NotifyConfig {
@@ -0,0 +1,203 @@
+use crate::{AppEvent, AppState};
+use axum::{extract::State, response::IntoResponse};
+use chat_core::User;
+use chrono::{Duration, Utc};
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Sse,
+    Ws,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    sse_connections: AtomicUsize,
+    ws_connections: AtomicUsize,
+    events_sent: AtomicU64,
+    lagged: AtomicU64,
+}
+
+impl Metrics {
+    fn connections(&self, transport: Transport) -> &AtomicUsize {
+        match transport {
+            Transport::Sse => &self.sse_connections,
+            Transport::Ws => &self.ws_connections,
+        }
+    }
+
+    pub(crate) fn events_sent(&self, n: usize) {
+        self.events_sent.fetch_add(n as _, Ordering::Relaxed);
+    }
+
+    /// Prometheus text format
+    fn render(&self, users: usize, channels: usize, listener_up: bool) -> String {
+        let mut out = String::new();
+        for (name, kind, help, values) in [
+            (
+                "notify_connections",
+                "gauge",
+                "Open event streams",
+                vec![
+                    (
+                        "{transport=\"sse\"}",
+                        self.sse_connections.load(Ordering::Relaxed) as u64,
+                    ),
+                    (
+                        "{transport=\"ws\"}",
+                        self.ws_connections.load(Ordering::Relaxed) as u64,
+                    ),
+                ],
+            ),
+            (
+                "notify_connected_users",
+                "gauge",
+                "Users with at least one open stream",
+                vec![("", users as u64)],
+            ),
+            (
+                "notify_user_channels",
+                "gauge",
+                "Per-user channels kept, including idle ones kept for replay",
+                vec![("", channels as u64)],
+            ),
+            (
+                "notify_pg_listener_up",
+                "gauge",
+                "Whether the postgres listener is connected",
+                vec![("", listener_up as u64)],
+            ),
+            (
+                "notify_events_sent_total",
+                "counter",
+                "Events delivered to open streams",
+                vec![("", self.events_sent.load(Ordering::Relaxed))],
+            ),
+            (
+                "notify_lagged_total",
+                "counter",
+                "Times a stream fell behind and dropped events",
+                vec![("", self.lagged.load(Ordering::Relaxed))],
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, v) in values {
+                let _ = writeln!(out, "{}{} {}", name, labels, v);
+            }
+        }
+        out
+    }
+}
+
+/// Tracks an open stream, the user stays online and keeps their channel until it is dropped
+pub(crate) struct ConnectionGuard {
+    state: AppState,
+    user_id: u64,
+    ws_id: u64,
+    transport: Transport,
+}
+
+impl ConnectionGuard {
+    pub(crate) fn new(state: &AppState, user: &User, transport: Transport) -> Self {
+        let user_id = user.id as u64;
+        let ws_id = user.ws_id as u64;
+        state
+            .metrics
+            .connections(transport)
+            .fetch_add(1, Ordering::Relaxed);
+        let presence = state.presence.connect(user_id, ws_id, Utc::now());
+        state.broadcast_presence(ws_id, presence);
+        Self {
+            state: state.clone(),
+            user_id,
+            ws_id,
+            transport,
+        }
+    }
+
+    /// The stream fell behind the channel and lost events, returns the event telling the client
+    pub(crate) fn lagged(&self, missed: u64) -> AppEvent {
+        warn!("User {} missed {} events", self.user_id, missed);
+        self.state.metrics.lagged.fetch_add(1, Ordering::Relaxed);
+        AppEvent::Lagged { missed }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let now = Utc::now();
+        let state = &self.state;
+        state
+            .metrics
+            .connections(self.transport)
+            .fetch_sub(1, Ordering::Relaxed);
+        if let Some(channel) = state.users.get(&self.user_id) {
+            channel.release(now);
+        }
+        if state.config.server.event_log_retention_secs == 0 {
+            state.remove_idle_channels(now);
+        }
+        let presence = state.presence.disconnect(self.user_id, self.ws_id, now);
+        state.broadcast_presence(self.ws_id, presence);
+    }
+}
+
+impl AppState {
+    /// Drop the channels of users who have been gone for longer than the retention
+    pub(crate) fn remove_idle_channels(&self, now: chrono::DateTime<Utc>) {
+        let retention = Duration::seconds(self.config.server.event_log_retention_secs as _);
+        let before = self.users.len();
+        self.users
+            .retain(|_, channel| !channel.idle_since(now - retention));
+        let removed = before.saturating_sub(self.users.len());
+        if removed > 0 {
+            info!("Removed {} idle user channels", removed);
+        }
+    }
+}
+
+pub fn setup_channel_cleanup(state: AppState) {
+    let secs = state.config.server.event_log_retention_secs.clamp(1, 30);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            state.remove_idle_channels(Utc::now());
+        }
+    });
+}
+
+pub(crate) async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let users = state
+        .users
+        .iter()
+        .filter(|channel| channel.connections() > 0)
+        .count();
+    let listener_up = state.listener_health().connected;
+    state.metrics.render(users, state.users.len(), listener_up)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_should_render() {
+        let metrics = Metrics::default();
+        metrics
+            .connections(Transport::Sse)
+            .fetch_add(2, Ordering::Relaxed);
+        metrics.events_sent(3);
+        let out = metrics.render(1, 4, true);
+        assert!(out.contains("notify_connections{transport=\"sse\"} 2\n"));
+        assert!(out.contains("notify_connections{transport=\"ws\"} 0\n"));
+        assert!(out.contains("notify_user_channels 4\n"));
+        assert!(out.contains("notify_pg_listener_up 1\n"));
+        assert!(out.contains("notify_events_sent_total 3\n"));
+    }
+}
@@ -14,6 +14,15 @@ pub enum AppError {
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
+    #[error("sql error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    #[error("session has been revoked or expired")]
+    SessionRevoked,
+
     #[error("jwt error: {0}")]
     JwtError(#[from] jwt_simple::Error),
 }
@@ -30,6 +39,9 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response<axum::body::Body> {
         let status = match self {
             AppError::JwtError(_) => StatusCode::FORBIDDEN,
+            AppError::SessionRevoked => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::SqlxError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
@@ -0,0 +1,208 @@
+use crate::AppEvent;
+use chrono::{DateTime, Utc};
+use std::{collections::VecDeque, sync::Arc, sync::Mutex};
+use tokio::sync::broadcast;
+
+/// An event with its id, ids increase across all users
+#[derive(Debug, Clone)]
+pub struct LoggedEvent {
+    pub id: u64,
+    pub event: Arc<AppEvent>,
+}
+
+/// The client asked for events which are no longer kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResyncRequired;
+
+/// Live events of a user together with the recent ones for replay
+pub struct UserChannel {
+    tx: broadcast::Sender<LoggedEvent>,
+    log: Mutex<EventLog>,
+}
+
+struct EventLog {
+    capacity: usize,
+    /// open streams of the user and since when there are none
+    connections: usize,
+    idle_since: Option<DateTime<Utc>>,
+    /// events up to this id are unknown, either evicted or sent before the log existed
+    floor: u64,
+    events: VecDeque<LoggedEvent>,
+}
+
+impl UserChannel {
+    /// Start a log for events after `floor`
+    pub fn new(floor: u64, channel_capacity: usize, log_capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(channel_capacity);
+        Self {
+            tx,
+            log: Mutex::new(EventLog {
+                capacity: log_capacity,
+                connections: 0,
+                idle_since: None,
+                floor,
+                events: VecDeque::with_capacity(log_capacity),
+            }),
+        }
+    }
+
+    /// Log the event and push it to the connected receivers, returns how many got it
+    pub fn publish(&self, event: LoggedEvent) -> usize {
+        let mut log = self.log.lock().expect("event log lock poisoned");
+        if log.events.len() == log.capacity {
+            if let Some(evicted) = log.events.pop_front() {
+                log.floor = evicted.id;
+            }
+        }
+        log.events.push_back(event.clone());
+        // sent under the lock so a subscriber never misses or repeats what it replays
+        self.tx.send(event).unwrap_or_default()
+    }
+
+    /// Subscribe to live events, with the logged ones after `last_id` to replay first,
+    /// every subscription has to be released when its stream closes
+    pub fn subscribe(
+        &self,
+        last_id: Option<u64>,
+    ) -> (
+        broadcast::Receiver<LoggedEvent>,
+        Result<Vec<LoggedEvent>, ResyncRequired>,
+    ) {
+        let mut log = self.log.lock().expect("event log lock poisoned");
+        log.connections += 1;
+        log.idle_since = None;
+        let rx = self.tx.subscribe();
+        let replay = match last_id {
+            None => Ok(vec![]),
+            Some(id) if id < log.floor => Err(ResyncRequired),
+            Some(id) if log.events.back().is_some_and(|e| id > e.id) => Err(ResyncRequired),
+            Some(id) if log.events.is_empty() && id > log.floor => Err(ResyncRequired),
+            Some(id) => Ok(log.events.iter().filter(|e| e.id > id).cloned().collect()),
+        };
+        (rx, replay)
+    }
+
+    /// The logged events after `id`, for a connection which was already sent events up to `id`
+    pub fn events_after(&self, id: u64) -> Result<Vec<LoggedEvent>, ResyncRequired> {
+        let log = self.log.lock().expect("event log lock poisoned");
+        if id < log.floor {
+            return Err(ResyncRequired);
+        }
+        Ok(log.events.iter().filter(|e| e.id > id).cloned().collect())
+    }
+
+    pub fn release(&self, now: DateTime<Utc>) {
+        let mut log = self.log.lock().expect("event log lock poisoned");
+        log.connections = log.connections.saturating_sub(1);
+        if log.connections == 0 {
+            log.idle_since = Some(now);
+        }
+    }
+
+    /// Nobody has been connected since `before`, the channel can go
+    pub fn idle_since(&self, before: DateTime<Utc>) -> bool {
+        let log = self.log.lock().expect("event log lock poisoned");
+        log.connections == 0 && log.idle_since.is_some_and(|t| t <= before)
+    }
+
+    pub fn connections(&self) -> usize {
+        self.log
+            .lock()
+            .expect("event log lock poisoned")
+            .connections
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppEvent, Typing};
+    use chrono::Duration;
+
+    fn event(id: u64) -> LoggedEvent {
+        LoggedEvent {
+            id,
+            event: Arc::new(AppEvent::Typing(Typing {
+                chat_id: 1,
+                user_id: 1,
+                expires_at: Utc::now(),
+            })),
+        }
+    }
+
+    fn ids(events: Vec<LoggedEvent>) -> Vec<u64> {
+        events.into_iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn subscribe_should_replay_missed_events() {
+        let channel = UserChannel::new(10, 16, 3);
+        for id in [11, 13, 14] {
+            channel.publish(event(id));
+        }
+        let (_rx, replay) = channel.subscribe(Some(11));
+        assert_eq!(ids(replay.unwrap()), vec![13, 14]);
+        let (_rx, replay) = channel.subscribe(Some(10));
+        assert_eq!(ids(replay.unwrap()), vec![11, 13, 14]);
+        let (_rx, replay) = channel.subscribe(None);
+        assert!(replay.unwrap().is_empty());
+    }
+
+    #[test]
+    fn events_after_should_follow_the_log() {
+        let channel = UserChannel::new(10, 16, 2);
+        assert!(channel.events_after(12).unwrap().is_empty());
+        for id in [11, 13, 14] {
+            channel.publish(event(id));
+        }
+        assert_eq!(ids(channel.events_after(12).unwrap()), vec![13, 14]);
+        assert_eq!(ids(channel.events_after(11).unwrap()), vec![13, 14]);
+        // 11 was evicted
+        assert!(channel.events_after(10).is_err());
+    }
+
+    #[test]
+    fn subscribe_should_require_resync_for_lost_events() {
+        let channel = UserChannel::new(10, 16, 2);
+        // nothing happened since the log started
+        assert!(channel.subscribe(Some(10)).1.unwrap().is_empty());
+        // ids this log never handed out
+        assert_eq!(channel.subscribe(Some(9)).1.unwrap_err(), ResyncRequired);
+        assert_eq!(channel.subscribe(Some(11)).1.unwrap_err(), ResyncRequired);
+
+        for id in [11, 12, 13] {
+            channel.publish(event(id));
+        }
+        // 11 was evicted, so events after 10 can't be replayed anymore
+        assert_eq!(channel.subscribe(Some(10)).1.unwrap_err(), ResyncRequired);
+        assert_eq!(ids(channel.subscribe(Some(11)).1.unwrap()), vec![12, 13]);
+    }
+
+    #[test]
+    fn channel_should_be_idle_after_last_release() {
+        let channel = UserChannel::new(0, 16, 8);
+        let now = Utc::now();
+        let (_rx1, _) = channel.subscribe(None);
+        let (_rx2, _) = channel.subscribe(None);
+        channel.release(now);
+        assert!(!channel.idle_since(now));
+        channel.release(now);
+        assert_eq!(channel.connections(), 0);
+        assert!(channel.idle_since(now));
+        assert!(!channel.idle_since(now - Duration::seconds(1)));
+
+        // a reconnect keeps it
+        let (_rx3, _) = channel.subscribe(None);
+        assert!(!channel.idle_since(now));
+    }
+
+    #[tokio::test]
+    async fn live_events_should_follow_replay() {
+        let channel = UserChannel::new(0, 16, 8);
+        channel.publish(event(1));
+        let (mut rx, replay) = channel.subscribe(Some(0));
+        assert_eq!(ids(replay.unwrap()), vec![1]);
+        assert_eq!(channel.publish(event(2)), 1);
+        assert_eq!(rx.recv().await.unwrap().id, 2);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{config::FanOutBackend, AppEvent, AppState};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Channel the replicas exchange signals on when the postgres backend is used
+pub(crate) const FANOUT_CHANNEL: &str = "notify_fanout";
+/// signals waiting to be published, more are dropped rather than piling up
+const PUBLISH_QUEUE: usize = 1024;
+
+/// Who an event is meant for, resolved by every replica against its own connections
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum Audience {
+    Users(Vec<u64>),
+    /// everyone connected to the workspace
+    Workspace(u64),
+    /// the members of the chat but the one typing, looked up by every replica
+    /// so the payload stays small however large the chat is
+    Chat(u64),
+}
+
+/// What one replica publishes for the others
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub node: Uuid,
+    #[serde(flatten)]
+    pub signal: Signal,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Signal {
+    Event {
+        audience: Audience,
+        event: Arc<AppEvent>,
+    },
+    /// the replica is still alive, what it reported about presence still holds
+    Heartbeat,
+}
+
+impl Envelope {
+    pub fn event(node: Uuid, audience: Audience, event: Arc<AppEvent>) -> Self {
+        Self {
+            node,
+            signal: Signal::Event { audience, event },
+        }
+    }
+}
+
+/// Passes events which don't come from the database on to the other replicas,
+/// database triggered events already reach every replica through its own listener
+pub trait FanOut: Send + Sync {
+    fn publish(&self, envelope: &Envelope);
+}
+
+/// A single replica, everything is delivered locally
+pub struct InProcessFanOut;
+
+impl FanOut for InProcessFanOut {
+    fn publish(&self, _envelope: &Envelope) {}
+}
+
+/// Replicas sharing the database exchange signals through pg_notify
+pub struct PgFanOut {
+    tx: mpsc::Sender<String>,
+}
+
+impl PgFanOut {
+    /// Publishing happens in the background so callers never wait on the database
+    pub fn new(pool: PgPool) -> Self {
+        let (tx, mut rx) = mpsc::channel::<String>(PUBLISH_QUEUE);
+        tokio::spawn(async move {
+            while let Some(payload) = rx.recv().await {
+                if let Err(e) = sqlx::query("SELECT pg_notify($1, $2)")
+                    .bind(FANOUT_CHANNEL)
+                    .bind(&payload)
+                    .execute(&pool)
+                    .await
+                {
+                    // signals are ephemeral, a lost one is replaced by the next
+                    warn!("Failed to publish to other replicas: {}", e);
+                }
+            }
+        });
+        Self { tx }
+    }
+}
+
+impl FanOut for PgFanOut {
+    fn publish(&self, envelope: &Envelope) {
+        match serde_json::to_string(envelope) {
+            Ok(payload) => match self.tx.try_send(payload) {
+                Ok(()) => {}
+                // signals are ephemeral, better lose some than fall further behind
+                Err(TrySendError::Full(_)) => warn!("Publish queue is full, dropped a signal"),
+                Err(TrySendError::Closed(_)) => warn!("Publisher is gone, dropped a signal"),
+            },
+            Err(e) => warn!("Failed to serialize envelope: {}", e),
+        }
+    }
+}
+
+pub(crate) fn new_fan_out(backend: FanOutBackend, pool: &PgPool) -> Box<dyn FanOut> {
+    match backend {
+        FanOutBackend::InProcess => Box::new(InProcessFanOut),
+        FanOutBackend::Postgres => Box::new(PgFanOut::new(pool.clone())),
+    }
+}
+
+impl AppState {
+    /// Deliver to the audience connected here and let the other replicas do the same
+    pub(crate) async fn fan_out(
+        &self,
+        audience: Audience,
+        event: Arc<AppEvent>,
+    ) -> Result<(), sqlx::Error> {
+        self.deliver(&audience, event.clone()).await?;
+        self.fan_out
+            .publish(&Envelope::event(self.node_id, audience, event));
+        Ok(())
+    }
+
+    /// An envelope from another replica, our own come back as well and are skipped
+    pub(crate) async fn receive_envelope(&self, payload: &str) -> anyhow::Result<()> {
+        let envelope: Envelope = serde_json::from_str(payload)?;
+        if envelope.node == self.node_id {
+            return Ok(());
+        }
+        let now = Utc::now();
+        let (audience, event) = match envelope.signal {
+            Signal::Event { audience, event } => (audience, event),
+            Signal::Heartbeat => {
+                self.presence.heard_from(envelope.node, now);
+                return Ok(());
+            }
+        };
+        info!("Received {} from replica {}", event.name(), envelope.node);
+        match (&audience, event.as_ref()) {
+            // merged with what this replica knows before anyone is told
+            (Audience::Workspace(ws_id), AppEvent::PresenceChanged(presence)) => {
+                self.presence
+                    .apply_remote(envelope.node, *ws_id, presence.clone(), now);
+                self.show_presence(presence.user_id, *ws_id);
+            }
+            _ => self.deliver(&audience, event).await?,
+        }
+        Ok(())
+    }
+
+    async fn deliver(&self, audience: &Audience, event: Arc<AppEvent>) -> Result<(), sqlx::Error> {
+        match audience {
+            Audience::Users(user_ids) => self.send_event(user_ids.iter().copied(), event),
+            Audience::Workspace(ws_id) => self.send_event(self.presence.online_in(*ws_id), event),
+            Audience::Chat(chat_id) => {
+                let Some(chat) = self.load_chat(*chat_id as _).await? else {
+                    return Ok(());
+                };
+                let typist = match event.as_ref() {
+                    AppEvent::Typing(typing) => Some(typing.user_id),
+                    _ => None,
+                };
+                let user_ids = chat
+                    .members
+                    .iter()
+                    .map(|id| *id as u64)
+                    .filter(|id| Some(*id) != typist);
+                self.send_event(user_ids, event)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tell the other replicas this one is alive, and take the users of replicas gone quiet offline
+pub(crate) fn setup_heartbeat(state: AppState) {
+    if state.config.fanout.backend == FanOutBackend::InProcess {
+        return;
+    }
+    let ttl = state.config.presence.replica_ttl_secs.max(3);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(ttl / 3));
+        loop {
+            interval.tick().await;
+            state.fan_out.publish(&Envelope {
+                node: state.node_id,
+                signal: Signal::Heartbeat,
+            });
+            for (user_id, ws_id) in state.presence.expire_replicas(Utc::now()) {
+                state.show_presence(user_id, ws_id);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Typing;
+    use chrono::Utc;
+
+    #[test]
+    fn envelope_should_round_trip() {
+        let envelope = Envelope::event(
+            Uuid::now_v7(),
+            Audience::Users(vec![2, 3]),
+            Arc::new(AppEvent::Typing(Typing {
+                chat_id: 1,
+                user_id: 1,
+                expires_at: Utc::now(),
+            })),
+        );
+        let payload = serde_json::to_string(&envelope).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(v["kind"], "event");
+        assert_eq!(v["audience"]["type"], "users");
+        assert_eq!(v["event"]["event"], "Typing");
+
+        let ret: Envelope = serde_json::from_str(&payload).unwrap();
+        assert_eq!(ret.node, envelope.node);
+        let Signal::Event { audience, event } = ret.signal else {
+            panic!("expected an event");
+        };
+        assert_eq!(audience, Audience::Users(vec![2, 3]));
+        assert_eq!(event.chat_id(), Some(1));
+
+        let heartbeat = Envelope {
+            node: Uuid::now_v7(),
+            signal: Signal::Heartbeat,
+        };
+        let v = serde_json::to_value(&heartbeat).unwrap();
+        assert_eq!(v["kind"], "heartbeat");
+
+        let v = serde_json::to_value(Audience::Workspace(1)).unwrap();
+        assert_eq!(v, serde_json::json!({"type": "workspace", "id": 1}));
+        // members are looked up by the receiver, large chats fit in a notification as well
+        let v = serde_json::to_value(Audience::Chat(7)).unwrap();
+        assert_eq!(v, serde_json::json!({"type": "chat", "id": 7}));
+    }
+}
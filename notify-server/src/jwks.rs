@@ -0,0 +1,99 @@
+use crate::AppState;
+use anyhow::{bail, Result};
+use chat_core::{DecodingKey, Jwks, TokenType};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Load the keys to verify notify_server tokens with, from chat-server's key set or from the configured pk,
+/// the pk keeps us starting while chat-server is down and the key set is fetched again later
+pub(crate) async fn load_decoding_key(url: Option<&str>, pk: Option<&str>) -> Result<DecodingKey> {
+    match (url, pk) {
+        (Some(url), None) => fetch_jwks(url).await,
+        (Some(url), Some(pk)) => match fetch_jwks(url).await {
+            Ok(dk) => Ok(dk),
+            Err(e) => {
+                warn!("Failed to fetch jwks from {}, using auth.pk: {}", url, e);
+                load_pk(pk)
+            }
+        },
+        (None, Some(pk)) => load_pk(pk),
+        (None, None) => bail!("either auth.jwks_url or auth.pk must be set"),
+    }
+}
+
+fn load_pk(pk: &str) -> Result<DecodingKey> {
+    Ok(DecodingKey::load(pk)?.with_token_type(TokenType::Notify))
+}
+
+async fn fetch_jwks(url: &str) -> Result<DecodingKey> {
+    let jwks: Jwks = reqwest::get(url).await?.error_for_status()?.json().await?;
+    info!("Loaded {} keys from {}", jwks.keys.len(), url);
+    Ok(DecodingKey::from_jwks(&jwks)?.with_token_type(TokenType::Notify))
+}
+
+/// Periodically fetch the key set again so rotated keys are picked up without a restart
+pub(crate) fn setup_jwks_refresh(state: AppState) {
+    let Some(url) = state.config.auth.jwks_url.clone() else {
+        return;
+    };
+    let period = Duration::from_secs(state.config.auth.jwks_refresh_secs);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        // the first tick completes immediately, keys were already loaded on startup
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match fetch_jwks(&url).await {
+                Ok(dk) => *state.dk.write().expect("dk lock poisoned") = dk,
+                Err(e) => warn!("Failed to refresh jwks from {}: {}", url, e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Json, Router};
+    use chat_core::{EncodingKey, User};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn load_decoding_key_from_jwks_should_work() -> Result<()> {
+        let ek = EncodingKey::load(include_str!("../../chat-core/fixture/encoding.pem"))?;
+        let dk = DecodingKey::load(include_str!("../../chat-core/fixture/decoding.pem"))?;
+        let jwks = dk.jwks();
+
+        let app = Router::new().route("/jwks.json", get(move || async move { Json(jwks) }));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let url = format!("http://{addr}/jwks.json");
+        let dk = load_decoding_key(Some(&url), None).await?;
+        let user = User::new(1, "alice", "alice@qq.com");
+        let token = ek.sign_notify(user.clone(), 1)?;
+        assert_eq!(dk.verify(&token)?.user_id, user.id);
+
+        assert!(load_decoding_key(None, None).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_decoding_key_should_fall_back_to_pk() -> Result<()> {
+        let ek = EncodingKey::load(include_str!("../../chat-core/fixture/encoding.pem"))?;
+        let pk = include_str!("../../chat-core/fixture/decoding.pem");
+        // nothing listens there, like chat-server being down
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/jwks.json", listener.local_addr()?);
+        drop(listener);
+
+        assert!(load_decoding_key(Some(&url), None).await.is_err());
+        let dk = load_decoding_key(Some(&url), Some(pk)).await?;
+        let user = User::new(1, "alice", "alice@qq.com");
+        let token = ek.sign_notify(user.clone(), 1)?;
+        assert_eq!(dk.verify(&token)?.user_id, user.id);
+        Ok(())
+    }
+}
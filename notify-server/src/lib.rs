@@ -1,26 +1,52 @@
 mod config;
+mod connection;
 mod error;
+mod event_log;
+mod fanout;
+mod jwks;
+mod loader;
 mod notify;
+mod presence;
 mod sse;
+mod ws;
 
+use anyhow::Context;
 use axum::{
     http::Method,
     middleware::from_fn_with_state,
     response::{Html, IntoResponse},
-    routing::get,
+    routing::{get, post},
     Router,
 };
-use chat_core::{verify_token, DecodingKey, TokenVerify, User};
-pub use config::NotifyConfig;
+use chat_core::{find_session_user, verify_token, DecodingKey, TokenVerify, User};
+use chrono::Utc;
+pub use config::{FanOutBackend, NotifyConfig};
+use connection::{metrics_handler, setup_channel_cleanup, Metrics};
 use dashmap::DashMap;
 use error::AppError;
-pub use notify::{setup_pg_listener, AppEvent};
+use event_log::{LoggedEvent, ResyncRequired, UserChannel};
+use fanout::{new_fan_out, setup_heartbeat};
+pub use fanout::{Audience, Envelope, FanOut, InProcessFanOut, PgFanOut, Signal};
+use jwks::{load_decoding_key, setup_jwks_refresh};
+use loader::ChatCache;
+use notify::ready_handler;
+pub use notify::{setup_pg_listener, AppEvent, ListenerHealth};
+use presence::{list_presence_handler, setup_presence_sweeper, typing_handler};
+pub use presence::{Presence, PresenceStatus, PresenceTracker, Typing};
+use sqlx::PgPool;
 use sse::sse_handler;
-use std::{ops::Deref, sync::Arc};
+use std::{
+    ops::Deref,
+    sync::{Arc, Mutex, RwLock},
+};
 use tokio::sync::broadcast;
 use tower_http::cors::{self, CorsLayer};
+use tracing::info;
+use uuid::Uuid;
+use ws::ws_handler;
+pub use ws::{ClientFrame, ServerFrame};
 
-pub type UserMap = Arc<DashMap<u64, broadcast::Sender<Arc<AppEvent>>>>;
+pub type UserMap = Arc<DashMap<u64, UserChannel>>;
 
 #[derive(Clone)]
 pub struct AppState(Arc<AppStateInner>);
@@ -28,14 +54,28 @@ pub struct AppState(Arc<AppStateInner>);
 pub struct AppStateInner {
     pub config: NotifyConfig,
     users: UserMap,
-    dk: DecodingKey,
+    presence: PresenceTracker,
+    chats: ChatCache,
+    metrics: Metrics,
+    listener: RwLock<ListenerHealth>,
+    /// tells this replica's own messages apart from the other replicas'
+    node_id: Uuid,
+    fan_out: Box<dyn FanOut>,
+    /// id of the last event sent, held while sending so ids reach every log in order
+    last_event_id: Mutex<u64>,
+    dk: RwLock<DecodingKey>,
+    pool: PgPool,
 }
 
 const INDEX_HTML: &str = include_str!("../index.html");
 
 pub async fn get_router(config: NotifyConfig) -> anyhow::Result<Router> {
-    let state = AppState::new(config);
+    let state = AppState::try_new(config).await?;
     setup_pg_listener(state.clone()).await?;
+    setup_jwks_refresh(state.clone());
+    setup_presence_sweeper(state.clone());
+    setup_channel_cleanup(state.clone());
+    setup_heartbeat(state.clone());
 
     let cors = CorsLayer::new()
         .allow_methods([
@@ -54,9 +94,14 @@ pub async fn get_router(config: NotifyConfig) -> anyhow::Result<Router> {
 
     let app = Router::new()
         .route("/events", get(sse_handler))
+        .route("/ws", get(ws_handler))
+        .route("/presence", get(list_presence_handler))
+        .route("/chats/{id}/typing", post(typing_handler))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .layer(cors)
         .route("/", get(index_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/ready", get(ready_handler))
         .with_state(state);
 
     Ok(app)
@@ -69,8 +114,11 @@ async fn index_handler() -> impl IntoResponse {
 impl TokenVerify for AppState {
     type Error = AppError;
 
-    fn verify(&self, token: &str) -> std::result::Result<User, Self::Error> {
-        Ok(self.0.dk.verify(token)?)
+    async fn verify(&self, token: &str, scope: &str) -> std::result::Result<User, Self::Error> {
+        let claims = self.dk.read().expect("dk lock poisoned").verify(token)?;
+        claims.require_scope(scope)?;
+        let user = find_session_user(&self.pool, &claims).await?;
+        user.ok_or(AppError::SessionRevoked)
     }
 }
 
@@ -82,14 +130,82 @@ impl Deref for AppState {
     }
 }
 
+const CHANNEL_CAPACITY: usize = 256;
+
 impl AppState {
-    pub fn new(config: NotifyConfig) -> Self {
-        let dk = DecodingKey::load(&config.auth.pk).expect("Failed to load public key");
-        Self(Arc::new(AppStateInner {
+    /// Events for the user, every connection of the user gets its own receiver,
+    /// the logged events after `last_id` come along to be replayed first
+    pub(crate) fn subscribe(
+        &self,
+        user_id: u64,
+        last_id: Option<u64>,
+    ) -> (
+        broadcast::Receiver<LoggedEvent>,
+        Result<Vec<LoggedEvent>, ResyncRequired>,
+    ) {
+        // read before touching the map, send_event takes the locks the other way round
+        let floor = *self.last_event_id.lock().expect("event id lock poisoned");
+        let channel = self.users.entry(user_id).or_insert_with(|| {
+            UserChannel::new(
+                floor,
+                CHANNEL_CAPACITY,
+                self.config.server.event_log_capacity,
+            )
+        });
+        channel.subscribe(last_id)
+    }
+
+    /// The logged events of the user after `last_id`, e.g. for a connection changing its filter
+    pub(crate) fn events_after(
+        &self,
+        user_id: u64,
+        last_id: u64,
+    ) -> Result<Vec<LoggedEvent>, ResyncRequired> {
+        match self.users.get(&user_id) {
+            Some(channel) => channel.events_after(last_id),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Push the event to whoever of the users is connected
+    pub(crate) fn send_event(&self, user_ids: impl IntoIterator<Item = u64>, event: Arc<AppEvent>) {
+        let mut last_event_id = self.last_event_id.lock().expect("event id lock poisoned");
+        *last_event_id += 1;
+        let id = *last_event_id;
+        for user_id in user_ids {
+            if let Some(channel) = self.users.get(&user_id) {
+                let event = LoggedEvent {
+                    id,
+                    event: event.clone(),
+                };
+                let n = channel.publish(event);
+                if n > 0 {
+                    info!("Sent event {} to user {}", id, user_id);
+                    self.metrics.events_sent(n);
+                }
+            }
+        }
+    }
+
+    pub async fn try_new(config: NotifyConfig) -> anyhow::Result<Self> {
+        let dk = load_decoding_key(config.auth.jwks_url.as_deref(), config.auth.pk.as_deref())
+            .await
+            .context("load decoding key failed")?;
+        let pool = PgPool::connect_lazy(&config.server.db_url).context("create db pool failed")?;
+        Ok(Self(Arc::new(AppStateInner {
+            presence: PresenceTracker::new(&config.presence),
+            chats: ChatCache::default(),
+            metrics: Metrics::default(),
+            listener: RwLock::new(ListenerHealth::default()),
+            node_id: Uuid::now_v7(),
+            fan_out: new_fan_out(config.fanout.backend, &pool),
+            // ids keep increasing across restarts, older ones can't be replayed
+            last_event_id: Mutex::new(Utc::now().timestamp_micros() as _),
             config,
-            dk,
+            dk: RwLock::new(dk),
             users: Arc::new(DashMap::default()),
-        }))
+            pool,
+        })))
     }
 }
 
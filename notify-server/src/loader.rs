@@ -0,0 +1,177 @@
+use crate::{notify::Change, AppState};
+use chat_core::{Chat, Message};
+use dashmap::DashMap;
+use sqlx::{query_as, FromRow};
+use std::{collections::HashMap, sync::Arc};
+
+const CHAT_CACHE_CAPACITY: usize = 4096;
+
+/// Chats by id, their members are needed to address almost every event
+#[derive(Default)]
+pub struct ChatCache {
+    chats: DashMap<i64, Arc<Chat>>,
+}
+
+impl ChatCache {
+    fn get(&self, id: i64) -> Option<Arc<Chat>> {
+        self.chats.get(&id).map(|chat| chat.clone())
+    }
+
+    fn insert(&self, chat: Arc<Chat>) {
+        // simply start over when full, the chats are loaded again on demand
+        if self.chats.len() >= CHAT_CACHE_CAPACITY {
+            self.chats.clear();
+        }
+        self.chats.insert(chat.id, chat);
+    }
+
+    fn invalidate(&self, id: i64) {
+        self.chats.remove(&id);
+    }
+
+    /// Changes may have been missed, e.g. while the listener was reconnecting
+    pub(crate) fn clear(&self) {
+        self.chats.clear();
+    }
+}
+
+/// The rows a batch of changes refers to
+#[derive(Debug, Default)]
+pub struct Rows {
+    pub messages: HashMap<i64, Message>,
+    pub chats: HashMap<i64, Arc<Chat>>,
+    /// chats before an update or delete, by change id
+    pub old_chats: HashMap<i64, Chat>,
+}
+
+#[derive(Debug, FromRow)]
+struct ChatChange {
+    change_id: i64,
+    #[sqlx(flatten)]
+    chat: Chat,
+}
+
+impl AppState {
+    /// A chat from the cache, loaded on a miss
+    pub(crate) async fn load_chat(&self, id: i64) -> Result<Option<Arc<Chat>>, sqlx::Error> {
+        if let Some(chat) = self.chats.get(id) {
+            return Ok(Some(chat));
+        }
+        let chat: Option<Chat> = query_as(
+            "SELECT id, ws_id, name, type, members, topic, description, icon, archived_at, created_at FROM chats WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(chat.map(|chat| {
+            let chat = Arc::new(chat);
+            self.chats.insert(chat.clone());
+            chat
+        }))
+    }
+
+    /// Load what the changes refer to, a few queries for the whole batch
+    pub(crate) async fn load_rows(&self, changes: &[Change]) -> Result<Rows, sqlx::Error> {
+        let mut rows = Rows::default();
+        let mut change_ids = vec![];
+        let mut chat_ids = vec![];
+        for change in changes {
+            match change {
+                Change::Chat(payload) => {
+                    self.chats.invalidate(payload.id);
+                    chat_ids.push(payload.id);
+                    change_ids.extend(payload.change_id);
+                }
+                Change::ReactionAdded(changed) | Change::ReactionRemoved(changed) => {
+                    chat_ids.push(changed.chat_id)
+                }
+                _ => {}
+            }
+        }
+
+        let message_ids: Vec<i64> = changes.iter().flat_map(Change::message_ids).collect();
+        if !message_ids.is_empty() {
+            let messages: Vec<Message> = query_as("SELECT id, chat_id, sender_id, content, files, created_at, edited_at, deleted_at, deleted_by, parent_id, reply_count, last_reply_at FROM messages WHERE id = ANY($1)")
+                .bind(&message_ids)
+                .fetch_all(&self.pool)
+                .await?;
+            chat_ids.extend(messages.iter().map(|m| m.chat_id));
+            rows.messages = messages.into_iter().map(|m| (m.id, m)).collect();
+        }
+
+        let mut missing = vec![];
+        for id in chat_ids {
+            match self.chats.get(id) {
+                Some(chat) => {
+                    rows.chats.insert(id, chat);
+                }
+                None => missing.push(id),
+            }
+        }
+        if !missing.is_empty() {
+            let chats: Vec<Chat> = query_as(
+                "SELECT id, ws_id, name, type, members, topic, description, icon, archived_at, created_at FROM chats WHERE id = ANY($1)",
+            )
+            .bind(&missing)
+            .fetch_all(&self.pool)
+            .await?;
+            for chat in chats {
+                let chat = Arc::new(chat);
+                self.chats.insert(chat.clone());
+                rows.chats.insert(chat.id, chat);
+            }
+        }
+
+        if !change_ids.is_empty() {
+            let changes: Vec<ChatChange> = query_as(
+                "SELECT id AS change_id, chat_id AS id, ws_id, name, type, members, topic, description, icon, archived_at, created_at FROM chat_changes WHERE id = ANY($1)",
+            )
+            .bind(&change_ids)
+            .fetch_all(&self.pool)
+            .await?;
+            rows.old_chats = changes.into_iter().map(|c| (c.change_id, c.chat)).collect();
+        }
+
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::ChatType;
+    use chrono::Utc;
+
+    #[test]
+    fn chat_cache_should_stay_bounded() {
+        let cache = ChatCache::default();
+        let chat = |id| {
+            Arc::new(Chat {
+                id,
+                ws_id: 1,
+                name: None,
+                r#type: ChatType::Group,
+                members: vec![1, 2, 3],
+                topic: None,
+                description: None,
+                icon: None,
+                archived_at: None,
+                created_at: Utc::now(),
+                last_read_id: 0,
+                unread_count: 0,
+                mention_count: 0,
+            })
+        };
+        for id in 0..CHAT_CACHE_CAPACITY as i64 {
+            cache.insert(chat(id));
+        }
+        assert!(cache.get(1).is_some());
+        cache.invalidate(1);
+        assert!(cache.get(1).is_none());
+
+        cache.insert(chat(1));
+        cache.insert(chat(-1));
+        assert_eq!(cache.chats.len(), 1);
+        assert!(cache.get(-1).is_some());
+    }
+}
@@ -1,116 +1,736 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
-use crate::AppState;
-use chat_core::{Chat, Message};
-use futures::StreamExt;
+use crate::{fanout::FANOUT_CHANNEL, loader::Rows, AppState, FanOutBackend, Presence, Typing};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use chat_core::{Chat, ChatRead, Mention, MentionKind, Message, Reaction};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgListener;
+use sqlx::postgres::{PgListener, PgNotification};
+use tokio::time::sleep;
 use tracing::{info, warn};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "event")]
 pub enum AppEvent {
     NewChat(Chat),
-    AddToChat(Chat),
-    RemoveFromChat(Chat),
+    MembersAdded(MembersChanged),
+    MembersRemoved(MembersChanged),
+    ChatRenamed(Chat),
+    /// topic, description, icon or archival changed
+    ChatUpdated(Chat),
+    ChatDeleted {
+        chat_id: i64,
+    },
     NewMessage(Message),
+    MessageUpdated(Message),
+    MessageDeleted(Message),
+    ThreadReplied(Box<ThreadReply>),
+    ReactionAdded(ReactionChanged),
+    ReactionRemoved(ReactionChanged),
+    Mentioned(Message),
+    ChatRead(ChatRead),
+    PresenceChanged(Presence),
+    Typing(Typing),
+    /// events since the client's last one are gone, it has to reload its state
+    ResyncRequired,
+    /// the connection fell behind and dropped events, the client should refetch
+    Lagged {
+        missed: u64,
+    },
+}
+
+/// A reply together with the parent carrying the new thread summary
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadReply {
+    pub message: Message,
+    pub parent: Message,
+}
+
+/// Who joined or left a chat, the members removed only get the notice without the chat
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MembersChanged {
+    pub chat_id: i64,
+    pub user_ids: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat: Option<Chat>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReactionChanged {
+    pub chat_id: i64,
+    pub reaction: Reaction,
+}
+
+impl AppEvent {
+    /// Name of the event on the wire
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppEvent::NewChat(_) => "NewChat",
+            AppEvent::MembersAdded(_) => "MembersAdded",
+            AppEvent::MembersRemoved(_) => "MembersRemoved",
+            AppEvent::ChatRenamed(_) => "ChatRenamed",
+            AppEvent::ChatUpdated(_) => "ChatUpdated",
+            AppEvent::ChatDeleted { .. } => "ChatDeleted",
+            AppEvent::NewMessage(_) => "NewMessage",
+            AppEvent::MessageUpdated(_) => "MessageUpdated",
+            AppEvent::MessageDeleted(_) => "MessageDeleted",
+            AppEvent::ThreadReplied(_) => "ThreadReplied",
+            AppEvent::ReactionAdded(_) => "ReactionAdded",
+            AppEvent::ReactionRemoved(_) => "ReactionRemoved",
+            AppEvent::Mentioned(_) => "Mentioned",
+            AppEvent::ChatRead(_) => "ChatRead",
+            AppEvent::PresenceChanged(_) => "PresenceChanged",
+            AppEvent::Typing(_) => "Typing",
+            AppEvent::ResyncRequired => "ResyncRequired",
+            AppEvent::Lagged { .. } => "Lagged",
+        }
+    }
+
+    /// The chat of events about what happens inside a chat
+    pub fn chat_id(&self) -> Option<u64> {
+        match self {
+            AppEvent::NewMessage(m)
+            | AppEvent::MessageUpdated(m)
+            | AppEvent::MessageDeleted(m)
+            | AppEvent::Mentioned(m) => Some(m.chat_id as _),
+            AppEvent::ChatRead(read) => Some(read.chat_id as _),
+            AppEvent::ThreadReplied(reply) => Some(reply.parent.chat_id as _),
+            AppEvent::ReactionAdded(r) | AppEvent::ReactionRemoved(r) => Some(r.chat_id as _),
+            AppEvent::Typing(typing) => Some(typing.chat_id),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
-struct Notification {
+pub(crate) struct Notification {
     // users being impacted, so we should send the notification to them
-    user_ids: HashSet<u64>,
-    event: Arc<AppEvent>,
+    pub(crate) user_ids: HashSet<u64>,
+    pub(crate) event: Arc<AppEvent>,
+}
+
+// triggers only send ids, pg_notify payloads are limited to 8000 bytes,
+// the rows are loaded by notify-server before the events are built
+
+// pg_notify('chat_updated', json_build_object('op', TG_OP, 'id', id, 'change_id', change_id)::text);
+// the change holds the chat as it was before an UPDATE or DELETE
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ChatUpdated {
+    pub(crate) op: String,
+    pub(crate) id: i64,
+    #[serde(default)]
+    pub(crate) change_id: Option<i64>,
+}
+
+// pg_notify('chat_message_created' | 'chat_message_updated' | 'chat_message_deleted', json_build_object('id', NEW.id)::text);
+#[derive(Debug, Serialize, Deserialize)]
+struct MessageChanged {
+    id: i64,
+}
+
+// pg_notify('chat_message_mentioned', json_build_object('id', msg_id, 'mentions', added)::text);
+#[derive(Debug, Serialize, Deserialize)]
+struct MessageMentioned {
+    id: i64,
+    /// only the mentions the statement inserted, those kept by an edit aren't notified again
+    mentions: Vec<Mention>,
 }
 
-// pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW)::text);
+// pg_notify('chat_thread_replied', json_build_object('id', NEW.id, 'parent_id', NEW.parent_id)::text);
 #[derive(Debug, Serialize, Deserialize)]
-struct ChatUpdated {
-    op: String,
-    old: Option<Chat>,
-    new: Option<Chat>,
+struct ThreadRepliedTo {
+    id: i64,
+    parent_id: i64,
 }
 
-// pg_notify('chat_message_created', row_to_json(NEW)::text);
+// pg_notify('chat_reaction_added' | 'chat_reaction_removed', json_build_object('reaction', reaction, 'chat_id', chat)::text);
 #[derive(Debug, Serialize, Deserialize)]
-struct ChatMessageCreated {
-    message: Message,
-    members: Vec<i64>,
+struct ChatReactionChanged {
+    reaction: Reaction,
+    chat_id: i64,
 }
 
+// pg_notify('chat_read_updated', json_build_object('read', NEW)::text);
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatReadUpdated {
+    read: ChatRead,
+}
+
+/// What a trigger reported, turned into a notification once the rows are loaded
+#[derive(Debug)]
+pub(crate) enum Change {
+    Chat(ChatUpdated),
+    MessageCreated(i64),
+    MessageUpdated(i64),
+    MessageDeleted(i64),
+    ThreadReplied { id: i64, parent_id: i64 },
+    ReactionAdded(ReactionChanged),
+    ReactionRemoved(ReactionChanged),
+    Mentioned { id: i64, mentions: Vec<Mention> },
+    Read(ChatRead),
+}
+
+const CHANNELS: [&str; 9] = [
+    "chat_updated",
+    "chat_message_created",
+    "chat_message_updated",
+    "chat_message_deleted",
+    "chat_thread_replied",
+    "chat_reaction_added",
+    "chat_reaction_removed",
+    "chat_message_mentioned",
+    "chat_read_updated",
+];
+
+/// notifications loaded together at most
+const MAX_BATCH: usize = 128;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// State of the postgres listener, notify-server is only ready while it is connected
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ListenerHealth {
+    pub connected: bool,
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl ListenerHealth {
+    fn connected(&mut self) {
+        if self.since.is_some() {
+            self.reconnects += 1;
+        }
+        self.connected = true;
+        self.since = Some(Utc::now());
+    }
+
+    fn failed(&mut self, e: &anyhow::Error) {
+        if self.connected {
+            self.since = Some(Utc::now());
+        }
+        self.connected = false;
+        self.last_error = Some(e.to_string());
+    }
+}
+
+/// Connect before serving so a bad db_url fails startup, then keep the listener
+/// running in the background, reconnecting with backoff whenever it drops
 pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
+    let listener = connect_listener(&state).await?;
+    tokio::spawn(run_listener(state, listener));
+
+    Ok(())
+}
+
+async fn connect_listener(state: &AppState) -> anyhow::Result<PgListener> {
     let mut listener = PgListener::connect(&state.config.server.db_url).await?;
-    listener.listen("chat_updated").await?;
-    listener.listen("chat_message_created").await?;
+    listener.listen_all(CHANNELS).await?;
+    if state.config.fanout.backend == FanOutBackend::Postgres {
+        listener.listen(FANOUT_CHANNEL).await?;
+    }
+    Ok(listener)
+}
 
-    let mut stream = listener.into_stream();
+async fn run_listener(state: AppState, listener: PgListener) {
+    let mut listener = Some(listener);
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let mut l = match listener.take() {
+            Some(l) => l,
+            None => match connect_listener(&state).await {
+                Ok(l) => {
+                    info!("Postgres listener reconnected");
+                    l
+                }
+                Err(e) => {
+                    warn!(
+                        "Postgres listener reconnect failed, retry in {:?}: {}",
+                        backoff, e
+                    );
+                    state.update_listener_health(|h| h.failed(&e));
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            },
+        };
+        state.update_listener_health(ListenerHealth::connected);
+        state.chats.clear();
+        backoff = MIN_BACKOFF;
 
-    tokio::spawn(async move {
-        while let Some(Ok(notif)) = stream.next().await {
-            info!("Received notification: {:?}", notif);
-            let notification = Notification::load(notif.channel(), notif.payload())?;
-            let users = &state.users;
-            for user_id in notification.user_ids {
-                if let Some(tx) = users.get(&user_id) {
-                    info!("Sending notification to user {}", user_id);
-                    if let Err(e) = tx.send(notification.event.clone()) {
-                        warn!("Failed to send notification to user {}: {}", user_id, e);
+        let e = loop {
+            match l.try_recv().await {
+                Ok(Some(notif)) => {
+                    // whatever else already arrived is loaded together
+                    let mut batch = vec![notif];
+                    while batch.len() < MAX_BATCH {
+                        match l.next_buffered() {
+                            Some(notif) => batch.push(notif),
+                            None => break,
+                        }
                     }
+                    state.handle_notifications(batch).await;
+                }
+                // notifications sent while reconnecting are lost either way
+                Ok(None) => break anyhow::anyhow!("connection to postgres lost"),
+                Err(e) => break e.into(),
+            }
+        };
+        warn!("Postgres listener stopped: {}", e);
+        state.update_listener_health(|h| h.failed(&e));
+    }
+}
+
+impl AppState {
+    async fn handle_notifications(&self, batch: Vec<PgNotification>) {
+        let mut changes = Vec::with_capacity(batch.len());
+        for notif in batch {
+            if notif.channel() == FANOUT_CHANNEL {
+                if let Err(e) = self.receive_envelope(notif.payload()).await {
+                    warn!("Skipped malformed envelope: {}", e);
+                }
+                continue;
+            }
+            info!("Received notification: {:?}", notif);
+            match Change::parse(notif.channel(), notif.payload()) {
+                Ok(change) => changes.push(change),
+                Err(e) => warn!(
+                    "Skipped malformed notification on {}: {}",
+                    notif.channel(),
+                    e
+                ),
+            }
+        }
+        if changes.is_empty() {
+            return;
+        }
+
+        match self.load_rows(&changes).await {
+            Ok(rows) => {
+                for notification in changes
+                    .into_iter()
+                    .flat_map(|c| c.into_notifications(&rows))
+                {
+                    self.send_event(notification.user_ids, notification.event)
                 }
             }
+            Err(e) => warn!(
+                "Dropped {} notifications, loading rows failed: {}",
+                changes.len(),
+                e
+            ),
         }
-        Ok::<_, anyhow::Error>(())
-    });
+    }
 
-    Ok(())
+    fn update_listener_health(&self, f: impl FnOnce(&mut ListenerHealth)) {
+        f(&mut self.listener.write().expect("listener lock poisoned"));
+    }
+
+    pub fn listener_health(&self) -> ListenerHealth {
+        self.listener
+            .read()
+            .expect("listener lock poisoned")
+            .clone()
+    }
+}
+
+pub(crate) async fn ready_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let health = state.listener_health();
+    let status = if health.connected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(health))
 }
 
-impl Notification {
-    fn load(r#type: &str, payload: &str) -> anyhow::Result<Self> {
-        match r#type {
-            "chat_updated" => {
-                let payload: ChatUpdated = serde_json::from_str(payload)?;
+impl Change {
+    pub(crate) fn parse(r#type: &str, payload: &str) -> anyhow::Result<Self> {
+        let message_id = |payload| -> anyhow::Result<i64> {
+            Ok(serde_json::from_str::<MessageChanged>(payload)?.id)
+        };
+        let change = match r#type {
+            "chat_updated" => Change::Chat(serde_json::from_str(payload)?),
+            "chat_message_created" => Change::MessageCreated(message_id(payload)?),
+            "chat_message_updated" => Change::MessageUpdated(message_id(payload)?),
+            "chat_message_deleted" => Change::MessageDeleted(message_id(payload)?),
+            "chat_message_mentioned" => {
+                let payload: MessageMentioned = serde_json::from_str(payload)?;
+                Change::Mentioned {
+                    id: payload.id,
+                    mentions: payload.mentions,
+                }
+            }
+            "chat_thread_replied" => {
+                let payload: ThreadRepliedTo = serde_json::from_str(payload)?;
+                Change::ThreadReplied {
+                    id: payload.id,
+                    parent_id: payload.parent_id,
+                }
+            }
+            "chat_reaction_added" | "chat_reaction_removed" => {
+                let payload: ChatReactionChanged = serde_json::from_str(payload)?;
+                let changed = ReactionChanged {
+                    chat_id: payload.chat_id,
+                    reaction: payload.reaction,
+                };
+                if r#type == "chat_reaction_added" {
+                    Change::ReactionAdded(changed)
+                } else {
+                    Change::ReactionRemoved(changed)
+                }
+            }
+            "chat_read_updated" => {
+                let payload: ChatReadUpdated = serde_json::from_str(payload)?;
+                Change::Read(payload.read)
+            }
+            _ => return Err(anyhow::anyhow!("Invalid notification type")),
+        };
+        Ok(change)
+    }
+
+    /// Messages the change is about
+    pub(crate) fn message_ids(&self) -> Vec<i64> {
+        match self {
+            Change::MessageCreated(id)
+            | Change::MessageUpdated(id)
+            | Change::MessageDeleted(id)
+            | Change::Mentioned { id, .. } => vec![*id],
+            Change::ThreadReplied { id, parent_id } => vec![*id, *parent_id],
+            _ => vec![],
+        }
+    }
+
+    /// Build the events from the loaded rows, changes whose rows are gone are dropped
+    pub(crate) fn into_notifications(self, rows: &Rows) -> Vec<Notification> {
+        match self {
+            Change::Chat(payload) => {
                 info!("ChatUpdated: {:?}", payload);
-                let user_ids =
-                    get_affected_chat_user_ids(payload.old.as_ref(), payload.new.as_ref());
-                let event = match payload.op.as_str() {
-                    "INSERT" => AppEvent::NewChat(payload.new.expect("new should exist")),
-                    "UPDATE" => AppEvent::AddToChat(payload.new.expect("new should exist")),
-                    "DELETE" => AppEvent::RemoveFromChat(payload.old.expect("old should exist")),
-                    _ => return Err(anyhow::anyhow!("Invalid operation")),
+                let old = payload.change_id.and_then(|id| rows.old_chats.get(&id));
+                let new = rows.chats.get(&payload.id).map(|chat| chat.as_ref());
+                get_chat_notifications(&payload.op, old, new)
+            }
+            change => change.into_notification(rows).into_iter().collect(),
+        }
+    }
+
+    fn into_notification(self, rows: &Rows) -> Option<Notification> {
+        let members = |chat_id: i64| -> HashSet<u64> {
+            rows.chats
+                .get(&chat_id)
+                .map(|chat| chat.members.iter().map(|v| *v as u64).collect())
+                .unwrap_or_default()
+        };
+        let load = |id: i64| rows.messages.get(&id).cloned();
+        let (user_ids, event) = match self {
+            Change::Chat(_) => return None,
+            Change::MessageCreated(id) => {
+                let message = load(id)?;
+                (members(message.chat_id), AppEvent::NewMessage(message))
+            }
+            Change::MessageUpdated(id) => {
+                let message = load(id)?;
+                (members(message.chat_id), AppEvent::MessageUpdated(message))
+            }
+            Change::MessageDeleted(id) => {
+                let message = load(id)?.into_tombstone();
+                (members(message.chat_id), AppEvent::MessageDeleted(message))
+            }
+            Change::ThreadReplied { id, parent_id } => {
+                let message = load(id)?;
+                let parent = load(parent_id)?;
+                (
+                    members(message.chat_id),
+                    AppEvent::ThreadReplied(Box::new(ThreadReply { message, parent })),
+                )
+            }
+            Change::ReactionAdded(changed) => {
+                (members(changed.chat_id), AppEvent::ReactionAdded(changed))
+            }
+            Change::ReactionRemoved(changed) => {
+                (members(changed.chat_id), AppEvent::ReactionRemoved(changed))
+            }
+            Change::Mentioned { id, mentions } => {
+                let mut message = load(id)?;
+                message.mentions = mentions;
+                let user_ids = get_mentioned_user_ids(&message, &members(message.chat_id));
+                (user_ids, AppEvent::Mentioned(message))
+            }
+            Change::Read(read) => ([read.user_id as u64].into(), AppEvent::ChatRead(read)),
+        };
+        Some(Notification {
+            user_ids,
+            event: Arc::new(event),
+        })
+    }
+}
+
+/// Everyone mentioned, the whole chat for @channel and its alias @here, the sender excluded
+fn get_mentioned_user_ids(message: &Message, members: &HashSet<u64>) -> HashSet<u64> {
+    let mut user_ids: HashSet<u64> = message
+        .mentions
+        .iter()
+        .filter_map(|m| m.user_id)
+        .map(|id| id as u64)
+        .collect();
+    if message.mentions.iter().any(|m| m.kind != MentionKind::User) {
+        user_ids.extend(members);
+    }
+    user_ids.remove(&(message.sender_id as u64));
+    user_ids
+}
+
+/// Events of a chat change, computed from the chat before and after it
+fn get_chat_notifications(op: &str, old: Option<&Chat>, new: Option<&Chat>) -> Vec<Notification> {
+    let user_ids = |ids: &[i64]| -> HashSet<u64> { ids.iter().map(|v| *v as u64).collect() };
+    let notify = |user_ids: HashSet<u64>, event| Notification {
+        user_ids,
+        event: Arc::new(event),
+    };
+    match (op, old, new) {
+        ("INSERT", _, Some(new)) => vec![notify(
+            user_ids(&new.members),
+            AppEvent::NewChat(new.clone()),
+        )],
+        ("DELETE", Some(old), _) => vec![notify(
+            user_ids(&old.members),
+            AppEvent::ChatDeleted { chat_id: old.id },
+        )],
+        ("UPDATE", Some(old), Some(new)) => {
+            let added: Vec<i64> = new
+                .members
+                .iter()
+                .filter(|id| !old.members.contains(id))
+                .copied()
+                .collect();
+            let removed: Vec<i64> = old
+                .members
+                .iter()
+                .filter(|id| !new.members.contains(id))
+                .copied()
+                .collect();
+            let mut ret = vec![];
+            if !removed.is_empty() {
+                let changed = |chat| MembersChanged {
+                    chat_id: new.id,
+                    user_ids: removed.clone(),
+                    chat,
                 };
-                Ok(Self {
-                    user_ids,
-                    event: Arc::new(event),
-                })
+                ret.push(notify(
+                    user_ids(&removed),
+                    AppEvent::MembersRemoved(changed(None)),
+                ));
+                ret.push(notify(
+                    user_ids(&new.members),
+                    AppEvent::MembersRemoved(changed(Some(new.clone()))),
+                ));
+            }
+            if !added.is_empty() {
+                ret.push(notify(
+                    user_ids(&new.members),
+                    AppEvent::MembersAdded(MembersChanged {
+                        chat_id: new.id,
+                        user_ids: added.clone(),
+                        chat: Some(new.clone()),
+                    }),
+                ));
+            }
+            // the members just added already got the chat as it is now
+            let kept: HashSet<u64> = new
+                .members
+                .iter()
+                .filter(|id| !added.contains(id))
+                .map(|v| *v as u64)
+                .collect();
+            if old.name != new.name {
+                ret.push(notify(kept.clone(), AppEvent::ChatRenamed(new.clone())));
             }
-            "chat_message_created" => {
-                let payload: ChatMessageCreated = serde_json::from_str(payload)?;
-                let user_ids = payload.members.iter().map(|v| *v as u64).collect();
-                Ok(Self {
-                    user_ids,
-                    event: Arc::new(AppEvent::NewMessage(payload.message)),
-                })
+            if old.topic != new.topic
+                || old.description != new.description
+                || old.icon != new.icon
+                || old.archived_at != new.archived_at
+            {
+                ret.push(notify(kept, AppEvent::ChatUpdated(new.clone())));
             }
-            _ => Err(anyhow::anyhow!("Invalid notification type")),
+            ret
         }
+        _ => vec![],
     }
 }
 
-fn get_affected_chat_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet<u64> {
-    match (old, new) {
-        (Some(old), Some(new)) => {
-            // diff old/new members, if identical, no need to notify, otherwise notify the union of both
-            let old_user_ids: HashSet<_> = old.members.iter().map(|v| *v as u64).collect();
-            let new_user_ids: HashSet<_> = new.members.iter().map(|v| *v as u64).collect();
-            if old_user_ids == new_user_ids {
-                HashSet::new()
-            } else {
-                old_user_ids.union(&new_user_ids).copied().collect()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chat_core::ChatType;
+
+    fn message(id: i64, chat_id: i64, content: &str) -> Message {
+        Message {
+            id,
+            chat_id,
+            sender_id: 1,
+            content: content.to_string(),
+            files: vec![],
+            created_at: Utc::now(),
+            edited_at: None,
+            deleted_at: None,
+            deleted_by: None,
+            parent_id: None,
+            reply_count: 0,
+            last_reply_at: None,
+            reactions: vec![],
+            mentions: vec![],
+        }
+    }
+
+    fn chat(id: i64, members: Vec<i64>) -> Chat {
+        Chat {
+            id,
+            ws_id: 1,
+            name: Some("general".to_string()),
+            r#type: ChatType::PublicChannel,
+            members,
+            topic: None,
+            description: None,
+            icon: None,
+            archived_at: None,
+            created_at: Utc::now(),
+            last_read_id: 0,
+            unread_count: 0,
+            mention_count: 0,
+        }
+    }
+
+    fn build(r#type: &str, payload: &str, rows: &Rows) -> Option<Notification> {
+        let mut ret = Change::parse(r#type, payload)
+            .unwrap()
+            .into_notifications(rows);
+        assert!(ret.len() <= 1);
+        ret.pop()
+    }
+
+    #[test]
+    fn malformed_notification_should_fail_to_parse() {
+        assert!(Change::parse("chat_message_created", "{").is_err());
+        assert!(Change::parse("chat_message_created", r#"{"members":[1]}"#).is_err());
+        assert!(Change::parse("unknown", "{}").is_err());
+
+        let payload = r#"{"read":{"chat_id":1,"user_id":2,"last_read_id":3,"updated_at":"2025-03-28T16:09:32Z"}}"#;
+        let notification = build("chat_read_updated", payload, &Rows::default()).unwrap();
+        assert_eq!(notification.user_ids, [2].into());
+        assert_eq!(notification.event.name(), "ChatRead");
+    }
+
+    #[test]
+    fn notification_should_be_built_from_loaded_rows() {
+        let mut rows = Rows::default();
+        rows.chats.insert(1, Arc::new(chat(1, vec![1, 2, 3])));
+        let mut deleted = message(10, 1, "secret");
+        deleted.deleted_at = Some(Utc::now());
+        rows.messages.insert(10, deleted);
+        rows.messages.insert(11, message(11, 1, "@channel hi"));
+
+        let notification = build("chat_message_deleted", r#"{"id":10}"#, &rows).unwrap();
+        assert_eq!(notification.user_ids, [1, 2, 3].into());
+        match notification.event.as_ref() {
+            AppEvent::MessageDeleted(m) => assert!(m.content.is_empty()),
+            e => panic!("unexpected event: {:?}", e),
+        }
+
+        // the sender isn't told about their own mention
+        let payload = r#"{"id":11,"mentions":[{"kind":"channel","user_id":null}]}"#;
+        let notification = build("chat_message_mentioned", payload, &rows).unwrap();
+        assert_eq!(notification.user_ids, [2, 3].into());
+
+        // an edit adding @charlie to a message mentioning @bob only tells charlie
+        rows.messages.insert(12, message(12, 1, "@bob @charlie"));
+        let payload = r#"{"id":12,"mentions":[{"kind":"user","user_id":3}]}"#;
+        let notification = build("chat_message_mentioned", payload, &rows).unwrap();
+        assert_eq!(notification.user_ids, [3].into());
+        match notification.event.as_ref() {
+            AppEvent::Mentioned(m) => assert_eq!(m.mentions.len(), 1),
+            e => panic!("unexpected event: {:?}", e),
+        }
+
+        // rows which are gone by now are skipped
+        assert!(build("chat_message_created", r#"{"id":13}"#, &rows).is_none());
+    }
+
+    #[test]
+    fn chat_update_should_be_split_by_audience() {
+        let mut rows = Rows::default();
+        let mut renamed = chat(1, vec![1, 2, 4]);
+        renamed.name = Some("random".to_string());
+        renamed.archived_at = Some(Utc::now());
+        rows.chats.insert(1, Arc::new(renamed));
+        rows.old_chats.insert(7, chat(1, vec![1, 2, 3]));
+        rows.old_chats.insert(8, chat(2, vec![1, 2]));
+
+        let payload = r#"{"op":"UPDATE","id":1,"change_id":7}"#;
+        let change = Change::parse("chat_updated", payload).unwrap();
+        let ret: Vec<_> = change
+            .into_notifications(&rows)
+            .into_iter()
+            .map(|n| (n.user_ids, n.event))
+            .collect();
+        assert_eq!(ret.len(), 5);
+
+        // the member removed only gets the notice
+        assert_eq!(ret[0].0, [3].into());
+        match ret[0].1.as_ref() {
+            AppEvent::MembersRemoved(changed) => {
+                assert_eq!(changed.user_ids, vec![3]);
+                assert!(changed.chat.is_none());
             }
+            e => panic!("unexpected event: {:?}", e),
+        }
+        assert_eq!(ret[1].0, [1, 2, 4].into());
+        match ret[1].1.as_ref() {
+            AppEvent::MembersRemoved(changed) => assert!(changed.chat.is_some()),
+            e => panic!("unexpected event: {:?}", e),
         }
-        (Some(old), None) => old.members.iter().map(|v| *v as u64).collect(),
-        (None, Some(new)) => new.members.iter().map(|v| *v as u64).collect(),
-        _ => HashSet::new(),
+        assert_eq!(ret[2].0, [1, 2, 4].into());
+        match ret[2].1.as_ref() {
+            AppEvent::MembersAdded(changed) => assert_eq!(changed.user_ids, vec![4]),
+            e => panic!("unexpected event: {:?}", e),
+        }
+        assert_eq!(ret[3].0, [1, 2].into());
+        assert_eq!(ret[3].1.name(), "ChatRenamed");
+        assert_eq!(ret[4].0, [1, 2].into());
+        assert_eq!(ret[4].1.name(), "ChatUpdated");
+
+        let payload = r#"{"op":"DELETE","id":2,"change_id":8}"#;
+        let change = Change::parse("chat_updated", payload).unwrap();
+        let ret = change.into_notifications(&rows);
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].user_ids, [1, 2].into());
+        assert_eq!(ret[0].event.name(), "ChatDeleted");
+    }
+
+    #[test]
+    fn chat_update_without_changes_should_be_silent() {
+        let mut rows = Rows::default();
+        rows.chats.insert(1, Arc::new(chat(1, vec![1, 2])));
+        rows.old_chats.insert(7, chat(1, vec![2, 1]));
+        let change = Change::parse("chat_updated", r#"{"op":"UPDATE","id":1,"change_id":7}"#);
+        assert!(change.unwrap().into_notifications(&rows).is_empty());
+    }
+
+    #[test]
+    fn listener_health_should_count_reconnects() {
+        let mut health = ListenerHealth::default();
+        health.connected();
+        assert_eq!(health.reconnects, 0);
+        health.failed(&anyhow::anyhow!("connection to postgres lost"));
+        assert!(!health.connected);
+        assert_eq!(
+            health.last_error.as_deref(),
+            Some("connection to postgres lost")
+        );
+        health.connected();
+        assert!(health.connected);
+        assert_eq!(health.reconnects, 1);
     }
 }
@@ -0,0 +1,538 @@
+use crate::{config::PresenceConfig, error::AppError, AppEvent, AppState, Audience, Envelope};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tracing::info;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+impl PresenceStatus {
+    fn rank(&self) -> u8 {
+        match self {
+            PresenceStatus::Online => 2,
+            PresenceStatus::Away => 1,
+            PresenceStatus::Offline => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Presence {
+    pub user_id: u64,
+    pub status: PresenceStatus,
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Typing {
+    pub chat_id: u64,
+    pub user_id: u64,
+    /// clients stop showing the indicator after this unless it is repeated
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+struct UserPresence {
+    connections: usize,
+    status: PresenceStatus,
+    last_seen: DateTime<Utc>,
+}
+
+/// Ephemeral presence and typing state, derived from the connections to this server
+pub struct PresenceTracker {
+    away_after: Duration,
+    forget_after: Duration,
+    replica_ttl: Duration,
+    typing_ttl: Duration,
+    /// by user and workspace, a user connected to several workspaces shows in each
+    users: DashMap<(u64, u64), UserPresence>,
+    typing: DashMap<(u64, u64), DateTime<Utc>>,
+    /// last known presence of users connected to other replicas, by user and workspace,
+    /// then by replica
+    remote: DashMap<(u64, u64), HashMap<Uuid, Presence>>,
+    /// when the other replicas were last heard from
+    replicas: DashMap<Uuid, DateTime<Utc>>,
+    /// the status the users connected here were last told about
+    shown: DashMap<(u64, u64), PresenceStatus>,
+}
+
+impl PresenceTracker {
+    pub fn new(config: &PresenceConfig) -> Self {
+        Self {
+            away_after: Duration::seconds(config.away_after_secs as _),
+            forget_after: Duration::seconds(config.forget_offline_after_secs as _),
+            replica_ttl: Duration::seconds(config.replica_ttl_secs as _),
+            typing_ttl: Duration::seconds(config.typing_ttl_secs as _),
+            users: DashMap::new(),
+            typing: DashMap::new(),
+            remote: DashMap::new(),
+            replicas: DashMap::new(),
+            shown: DashMap::new(),
+        }
+    }
+
+    /// A new connection of the user, returns the presence if it changed
+    pub fn connect(&self, user_id: u64, ws_id: u64, now: DateTime<Utc>) -> Option<Presence> {
+        let mut entry = self.users.entry((user_id, ws_id)).or_insert(UserPresence {
+            connections: 0,
+            status: PresenceStatus::Offline,
+            last_seen: now,
+        });
+        entry.connections += 1;
+        entry.last_seen = now;
+        update_status(user_id, &mut entry, PresenceStatus::Online)
+    }
+
+    pub fn disconnect(&self, user_id: u64, ws_id: u64, now: DateTime<Utc>) -> Option<Presence> {
+        let mut entry = self.users.get_mut(&(user_id, ws_id))?;
+        entry.connections = entry.connections.saturating_sub(1);
+        if entry.connections > 0 {
+            return None;
+        }
+        entry.last_seen = now;
+        update_status(user_id, &mut entry, PresenceStatus::Offline)
+    }
+
+    /// Activity of a connected user brings them back from away
+    pub fn touch(&self, user_id: u64, ws_id: u64, now: DateTime<Utc>) -> Option<Presence> {
+        let mut entry = self.users.get_mut(&(user_id, ws_id))?;
+        if entry.connections == 0 {
+            return None;
+        }
+        entry.last_seen = now;
+        update_status(user_id, &mut entry, PresenceStatus::Online)
+    }
+
+    /// Mark idle users away, forget users long gone and expired typing signals,
+    /// returns the changes along with their workspace
+    pub fn sweep(&self, now: DateTime<Utc>) -> Vec<(u64, Presence)> {
+        self.typing.retain(|_, expires_at| *expires_at > now);
+        self.users
+            .retain(|_, entry| entry.connections > 0 || now - entry.last_seen < self.forget_after);
+        self.remote.retain(|_, by_replica| {
+            by_replica.retain(|_, presence| {
+                presence.status != PresenceStatus::Offline
+                    || now - presence.last_seen < self.forget_after
+            });
+            !by_replica.is_empty()
+        });
+        self.shown
+            .retain(|key, _| self.users.contains_key(key) || self.remote.contains_key(key));
+        self.users
+            .iter_mut()
+            .filter_map(|mut entry| {
+                let (user_id, ws_id) = *entry.key();
+                if entry.status == PresenceStatus::Online
+                    && now - entry.last_seen >= self.away_after
+                {
+                    update_status(user_id, &mut entry, PresenceStatus::Away)
+                        .map(|presence| (ws_id, presence))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Record a typing signal, returns it when members should be told,
+    /// repeated signals are only passed on once half of the previous one expired
+    pub fn typing(&self, chat_id: u64, user_id: u64, now: DateTime<Utc>) -> Option<Typing> {
+        let expires_at = now + self.typing_ttl;
+        let mut entry = self.typing.entry((chat_id, user_id)).or_insert(now);
+        if *entry - now > self.typing_ttl / 2 {
+            return None;
+        }
+        *entry = expires_at;
+        Some(Typing {
+            chat_id,
+            user_id,
+            expires_at,
+        })
+    }
+
+    /// A presence change reported by another replica
+    pub fn apply_remote(&self, node: Uuid, ws_id: u64, presence: Presence, now: DateTime<Utc>) {
+        self.heard_from(node, now);
+        self.remote
+            .entry((presence.user_id, ws_id))
+            .or_default()
+            .insert(node, presence);
+    }
+
+    pub fn heard_from(&self, node: Uuid, now: DateTime<Utc>) {
+        self.replicas.insert(node, now);
+    }
+
+    /// Users of replicas not heard from for a while are taken offline,
+    /// returns the users and workspaces affected
+    pub fn expire_replicas(&self, now: DateTime<Utc>) -> Vec<(u64, u64)> {
+        let mut gone = vec![];
+        self.replicas.retain(|node, seen| {
+            let alive = now - *seen < self.replica_ttl;
+            if !alive {
+                info!("Replica {} went quiet", node);
+                gone.push(*node);
+            }
+            alive
+        });
+        if gone.is_empty() {
+            return vec![];
+        }
+        let mut affected = vec![];
+        for mut entry in self.remote.iter_mut() {
+            let key = *entry.key();
+            for (node, presence) in entry.value_mut().iter_mut() {
+                if gone.contains(node) && presence.status != PresenceStatus::Offline {
+                    presence.status = PresenceStatus::Offline;
+                    affected.push(key);
+                }
+            }
+        }
+        affected
+    }
+
+    /// Presence of the user over all replicas, the most present status wins
+    fn merged(&self, user_id: u64, ws_id: u64) -> Option<Presence> {
+        let key = (user_id, ws_id);
+        let mut all: Vec<_> = self
+            .remote
+            .get(&key)
+            .map(|by_replica| by_replica.values().cloned().collect())
+            .unwrap_or_default();
+        if let Some(entry) = self.users.get(&key) {
+            all.push(presence(user_id, &entry));
+        }
+        all.into_iter().reduce(|a, b| Presence {
+            user_id,
+            status: if b.status.rank() > a.status.rank() {
+                b.status
+            } else {
+                a.status
+            },
+            last_seen: a.last_seen.max(b.last_seen),
+        })
+    }
+
+    /// The presence to tell the users connected here about, if it changed since they were
+    /// last told, e.g. a user leaving this replica stays online while connected to another
+    pub fn reconcile(&self, user_id: u64, ws_id: u64) -> Option<Presence> {
+        let merged = self.merged(user_id, ws_id)?;
+        let mut shown = self
+            .shown
+            .entry((user_id, ws_id))
+            .or_insert(PresenceStatus::Offline);
+        if *shown == merged.status {
+            return None;
+        }
+        *shown = merged.status;
+        Some(merged)
+    }
+
+    /// Presence of everyone seen in the workspace, on this replica or others
+    pub fn list(&self, ws_id: u64) -> Vec<Presence> {
+        let mut user_ids: Vec<_> = self
+            .users
+            .iter()
+            .map(|entry| *entry.key())
+            .chain(self.remote.iter().map(|entry| *entry.key()))
+            .filter(|(_, ws)| *ws == ws_id)
+            .map(|(user_id, _)| user_id)
+            .collect();
+        user_ids.sort();
+        user_ids.dedup();
+        user_ids
+            .into_iter()
+            .filter_map(|user_id| self.merged(user_id, ws_id))
+            .collect()
+    }
+
+    /// Connected users of the workspace, who get to see presence changes
+    pub fn online_in(&self, ws_id: u64) -> Vec<u64> {
+        self.users
+            .iter()
+            .filter(|entry| entry.key().1 == ws_id && entry.connections > 0)
+            .map(|entry| entry.key().0)
+            .collect()
+    }
+}
+
+fn update_status(
+    user_id: u64,
+    entry: &mut UserPresence,
+    status: PresenceStatus,
+) -> Option<Presence> {
+    if entry.status == status {
+        return None;
+    }
+    entry.status = status;
+    Some(presence(user_id, entry))
+}
+
+fn presence(user_id: u64, entry: &UserPresence) -> Presence {
+    Presence {
+        user_id,
+        status: entry.status,
+        last_seen: entry.last_seen,
+    }
+}
+
+impl AppState {
+    /// A presence change on this replica, the other replicas merge it with what they know
+    /// and everyone connected to the workspace hears of the merged presence
+    pub(crate) fn broadcast_presence(&self, ws_id: u64, presence: Option<Presence>) {
+        let Some(presence) = presence else {
+            return;
+        };
+        info!("Presence changed: {:?}", presence);
+        let user_id = presence.user_id;
+        self.fan_out.publish(&Envelope::event(
+            self.node_id,
+            Audience::Workspace(ws_id),
+            Arc::new(AppEvent::PresenceChanged(presence)),
+        ));
+        self.show_presence(user_id, ws_id);
+    }
+
+    /// Tell the users of the workspace connected here, if the merged presence changed
+    pub(crate) fn show_presence(&self, user_id: u64, ws_id: u64) {
+        if let Some(presence) = self.presence.reconcile(user_id, ws_id) {
+            self.send_event(
+                self.presence.online_in(ws_id),
+                Arc::new(AppEvent::PresenceChanged(presence)),
+            );
+        }
+    }
+}
+
+pub fn setup_presence_sweeper(state: AppState) {
+    let secs = state.config.presence.away_after_secs.clamp(1, 30);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            for (ws_id, presence) in state.presence.sweep(Utc::now()) {
+                state.broadcast_presence(ws_id, Some(presence));
+            }
+        }
+    });
+}
+
+pub(crate) async fn list_presence_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    Json(state.presence.list(user.ws_id as _))
+}
+
+pub(crate) async fn typing_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.send_typing(&user, chat_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+impl AppState {
+    /// Tell the other chat members that the user is typing
+    pub(crate) async fn send_typing(&self, user: &User, chat_id: u64) -> Result<(), AppError> {
+        // only chats of the workspace the token is scoped to
+        let member: Option<i32> = sqlx::query_scalar(
+            "SELECT 1 FROM chats WHERE id = $1 AND $2 = ANY(members) AND ws_id = $3",
+        )
+        .bind(chat_id as i64)
+        .bind(user.id)
+        .bind(user.ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        if member.is_none() {
+            return Err(AppError::NotFound(format!("chat id {}", chat_id)));
+        }
+
+        let user_id = user.id as u64;
+        let now = Utc::now();
+        let ws_id = user.ws_id as u64;
+        self.broadcast_presence(ws_id, self.presence.touch(user_id, ws_id, now));
+        if let Some(typing) = self.presence.typing(chat_id, user_id, now) {
+            self.fan_out(Audience::Chat(chat_id), Arc::new(AppEvent::Typing(typing)))
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> PresenceTracker {
+        PresenceTracker::new(&PresenceConfig {
+            away_after_secs: 60,
+            forget_offline_after_secs: 600,
+            replica_ttl_secs: 30,
+            typing_ttl_secs: 6,
+        })
+    }
+
+    #[test]
+    fn presence_should_follow_connections() {
+        let tracker = tracker();
+        let now = Utc::now();
+        let p = tracker.connect(1, 1, now).expect("should be online");
+        assert_eq!(p.status, PresenceStatus::Online);
+        // a second device doesn't change anything
+        assert!(tracker.connect(1, 1, now).is_none());
+        assert!(tracker.disconnect(1, 1, now).is_none());
+
+        let later = now + Duration::seconds(61);
+        let ret = tracker.sweep(later);
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].0, 1);
+        assert_eq!(ret[0].1.status, PresenceStatus::Away);
+        let p = tracker.touch(1, 1, later).expect("should be back online");
+        assert_eq!(p.status, PresenceStatus::Online);
+
+        let p = tracker.disconnect(1, 1, later).expect("should be offline");
+        assert_eq!(p.status, PresenceStatus::Offline);
+        assert_eq!(p.last_seen, later);
+        assert!(tracker.online_in(1).is_empty());
+        assert_eq!(tracker.list(1), vec![p]);
+
+        // gone for long enough to be forgotten
+        tracker.sweep(later + Duration::seconds(599));
+        assert_eq!(tracker.list(1).len(), 1);
+        tracker.sweep(later + Duration::seconds(600));
+        assert!(tracker.list(1).is_empty());
+        assert!(tracker.users.is_empty());
+    }
+
+    #[test]
+    fn presence_should_be_kept_per_workspace() {
+        let tracker = tracker();
+        let now = Utc::now();
+        assert!(tracker.connect(1, 1, now).is_some());
+        // connecting to another workspace doesn't take the user out of the first
+        assert!(tracker.connect(1, 2, now).is_some());
+        assert_eq!(tracker.online_in(1), vec![1]);
+        assert_eq!(tracker.online_in(2), vec![1]);
+
+        let p = tracker.disconnect(1, 2, now).expect("should be offline");
+        assert_eq!(p.status, PresenceStatus::Offline);
+        assert_eq!(tracker.online_in(1), vec![1]);
+        assert_eq!(tracker.list(1)[0].status, PresenceStatus::Online);
+        assert_eq!(tracker.list(2)[0].status, PresenceStatus::Offline);
+    }
+
+    #[test]
+    fn list_should_merge_remote_presence() {
+        let tracker = tracker();
+        let now = Utc::now();
+        let node = Uuid::now_v7();
+        tracker.connect(1, 1, now);
+        let remote = |user_id, status| Presence {
+            user_id,
+            status,
+            last_seen: now,
+        };
+        tracker.apply_remote(node, 1, remote(2, PresenceStatus::Online), now);
+        tracker.apply_remote(node, 2, remote(3, PresenceStatus::Online), now);
+        // another replica lost the user, but they are still connected here
+        tracker.apply_remote(node, 1, remote(1, PresenceStatus::Offline), now);
+
+        let ret = tracker.list(1);
+        assert_eq!(ret.len(), 2);
+        assert_eq!(ret[0].status, PresenceStatus::Online);
+        assert_eq!(ret[1], remote(2, PresenceStatus::Online));
+        // only the users connected here get events
+        assert_eq!(tracker.online_in(1), vec![1]);
+    }
+
+    #[test]
+    fn offline_should_wait_for_every_replica() {
+        let tracker = tracker();
+        let now = Utc::now();
+        let node = Uuid::now_v7();
+        let remote = |status| Presence {
+            user_id: 1,
+            status,
+            last_seen: now,
+        };
+        tracker.connect(1, 1, now);
+        assert_eq!(
+            tracker.reconcile(1, 1).unwrap().status,
+            PresenceStatus::Online
+        );
+        tracker.apply_remote(node, 1, remote(PresenceStatus::Online), now);
+        assert!(tracker.reconcile(1, 1).is_none());
+
+        // still connected to the other replica
+        assert!(tracker.disconnect(1, 1, now).is_some());
+        assert!(tracker.reconcile(1, 1).is_none());
+        tracker.apply_remote(node, 1, remote(PresenceStatus::Offline), now);
+        assert_eq!(
+            tracker.reconcile(1, 1).unwrap().status,
+            PresenceStatus::Offline
+        );
+    }
+
+    #[test]
+    fn quiet_replica_should_expire() {
+        let tracker = tracker();
+        let now = Utc::now();
+        let node = Uuid::now_v7();
+        let online = Presence {
+            user_id: 2,
+            status: PresenceStatus::Online,
+            last_seen: now,
+        };
+        tracker.apply_remote(node, 1, online, now);
+        assert_eq!(
+            tracker.reconcile(2, 1).unwrap().status,
+            PresenceStatus::Online
+        );
+
+        // heartbeats keep it alive
+        tracker.heard_from(node, now + Duration::seconds(20));
+        assert!(tracker
+            .expire_replicas(now + Duration::seconds(40))
+            .is_empty());
+        assert_eq!(
+            tracker.expire_replicas(now + Duration::seconds(50)),
+            vec![(2, 1)]
+        );
+        assert_eq!(
+            tracker.reconcile(2, 1).unwrap().status,
+            PresenceStatus::Offline
+        );
+        assert_eq!(tracker.list(1)[0].status, PresenceStatus::Offline);
+    }
+
+    #[test]
+    fn typing_should_be_throttled() {
+        let tracker = tracker();
+        let now = Utc::now();
+        let typing = tracker.typing(1, 1, now).expect("should be sent");
+        assert_eq!(typing.expires_at, now + Duration::seconds(6));
+        assert!(tracker.typing(1, 1, now + Duration::seconds(1)).is_none());
+        assert!(tracker.typing(1, 1, now + Duration::seconds(3)).is_some());
+
+        tracker.sweep(now + Duration::seconds(10));
+        assert!(tracker.typing.is_empty());
+    }
+}
@@ -1,6 +1,11 @@
-use crate::{AppEvent, AppState};
+use crate::{
+    connection::{ConnectionGuard, Transport},
+    event_log::LoggedEvent,
+    AppEvent, AppState,
+};
 use axum::{
     extract::State,
+    http::HeaderMap,
     response::{sse::Event, Sse},
     Extension,
 };
@@ -8,40 +13,41 @@ use axum_extra::{headers, TypedHeader};
 use chat_core::User;
 use futures::stream::Stream;
 use std::{convert::Infallible, time::Duration};
-use tokio::sync::broadcast;
-use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt,
+};
 use tracing::info;
 
-const CHANNEL_CAPACITY: usize = 256;
-
 pub(crate) async fn sse_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
+    headers: HeaderMap,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
     info!("`{}` connected", user_agent.as_str());
 
-    let user_id = user.id as u64;
-    let users = &state.users;
-
-    let rx = if let Some(tx) = users.get(&user_id) {
-        tx.subscribe()
-    } else {
-        let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
-        state.users.insert(user.id as u64, tx);
-        rx
+    // sent by the browser when it reconnects
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    let (rx, replay) = state.subscribe(user.id as _, last_event_id);
+    let replay: Vec<_> = match replay {
+        Ok(events) => events.iter().map(to_sse_event).collect(),
+        Err(_) => {
+            info!("User {} has to resync after {:?}", user.id, last_event_id);
+            vec![to_sse_event_without_id(&AppEvent::ResyncRequired)]
+        }
     };
+    // the guard lives as long as the stream, dropping it when the client goes away
+    let guard = ConnectionGuard::new(&state, &user, Transport::Sse);
 
-    let stream = BroadcastStream::new(rx).filter_map(|v| v.ok()).map(|v| {
-        let name = match v.as_ref() {
-            AppEvent::NewChat(_) => "NewChat",
-            AppEvent::AddToChat(_) => "AddToChat",
-            AppEvent::RemoveFromChat(_) => "RemoveFromChat",
-            AppEvent::NewMessage(_) => "NewMessage",
-        };
-        let v = serde_json::to_string(&v).expect("Failed to serialize event");
-        Ok(Event::default().data(v).event(name))
+    let live = BroadcastStream::new(rx).map(move |v| match v {
+        Ok(v) => to_sse_event(&v),
+        Err(BroadcastStreamRecvError::Lagged(n)) => to_sse_event_without_id(&guard.lagged(n)),
     });
+    let stream = tokio_stream::iter(replay).chain(live).map(Ok);
 
     Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
@@ -49,3 +55,13 @@ pub(crate) async fn sse_handler(
             .text("keep-alive-text"),
     )
 }
+
+fn to_sse_event(v: &LoggedEvent) -> Event {
+    to_sse_event_without_id(&v.event).id(v.id.to_string())
+}
+
+/// Events about the stream itself, they can't be resumed from
+fn to_sse_event_without_id(v: &AppEvent) -> Event {
+    let data = serde_json::to_string(v).expect("Failed to serialize event");
+    Event::default().data(data).event(v.name())
+}
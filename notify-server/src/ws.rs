@@ -0,0 +1,282 @@
+use crate::{
+    connection::{ConnectionGuard, Transport},
+    AppEvent, AppState, LoggedEvent,
+};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::IntoResponse,
+    Extension,
+};
+use chat_core::User;
+use chrono::Utc;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::info;
+
+/// Commands sent by the client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// only pass events of these chats on, an empty list means all chats,
+    /// the logged events after the last ack are sent again under the new filter
+    Subscribe {
+        chat_ids: Vec<u64>,
+    },
+    /// the client has handled the events up to this id, a new subscription replays from here
+    Ack {
+        id: u64,
+    },
+    Typing {
+        chat_id: u64,
+    },
+    Ping,
+}
+
+/// Frames sent to the client
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame<'a> {
+    /// `id` is left out when the event can't be resumed from, i.e. resync required
+    Event {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        data: &'a AppEvent,
+    },
+    Subscribed {
+        chat_ids: &'a [u64],
+    },
+    Pong,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct WsParams {
+    /// resume after this event, like Last-Event-ID for sse
+    #[serde(default)]
+    last_event_id: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Connection {
+    /// the last event passed through, whether it was sent or filtered out
+    last_id: u64,
+    acked: u64,
+    chat_ids: HashSet<u64>,
+}
+
+impl Connection {
+    fn new(last_id: u64) -> Self {
+        Self {
+            last_id,
+            acked: last_id,
+            ..Default::default()
+        }
+    }
+
+    fn wants(&self, event: &AppEvent) -> bool {
+        match event.chat_id() {
+            Some(chat_id) if !self.chat_ids.is_empty() => self.chat_ids.contains(&chat_id),
+            _ => true,
+        }
+    }
+
+    /// The frame for an event, replayed or live, unless the subscription filters it out
+    fn pass(&mut self, e: &LoggedEvent) -> Option<Message> {
+        self.last_id = self.last_id.max(e.id);
+        self.wants(&e.event).then(|| event_frame(e))
+    }
+}
+
+pub(crate) async fn ws_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(params): Query<WsParams>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, user, state, params.last_event_id))
+}
+
+async fn handle_socket(socket: WebSocket, user: User, state: AppState, last_id: Option<u64>) {
+    info!("User {} connected over websocket", user.id);
+    let (mut rx, replay) = state.subscribe(user.id as _, last_id);
+    let guard = ConnectionGuard::new(&state, &user, Transport::Ws);
+    let (mut sender, mut receiver) = socket.split();
+    let current_id = *state.last_event_id.lock().expect("event id lock poisoned");
+
+    let (mut conn, replay) = match (replay, last_id) {
+        (Ok(events), Some(last_id)) => {
+            let mut conn = Connection::new(last_id);
+            let frames = events.iter().filter_map(|e| conn.pass(e)).collect();
+            (conn, frames)
+        }
+        (Ok(_), None) => (Connection::new(current_id), vec![]),
+        (Err(_), _) => (Connection::new(current_id), vec![resync_frame()]),
+    };
+    for frame in replay {
+        if sender.send(frame).await.is_err() {
+            return;
+        }
+    }
+
+    'outer: loop {
+        let frames = tokio::select! {
+            ret = rx.recv() => match ret {
+                Ok(e) => match conn.pass(&e) {
+                    Some(frame) => vec![frame],
+                    None => continue,
+                },
+                Err(RecvError::Lagged(n)) => {
+                    vec![to_text(&ServerFrame::Event { id: None, data: &guard.lagged(n) })]
+                }
+                Err(RecvError::Closed) => break,
+            },
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    match serde_json::from_str::<ClientFrame>(&text) {
+                        Ok(cmd) => handle_command(&state, &user, &mut conn, cmd).await,
+                        Err(e) => vec![to_text(&ServerFrame::Error { message: e.to_string() })],
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // pings are answered by the websocket layer
+                Some(Ok(_)) => continue,
+            },
+        };
+        for frame in frames {
+            if sender.send(frame).await.is_err() {
+                break 'outer;
+            }
+        }
+    }
+    info!("User {} disconnected from websocket", user.id);
+}
+
+async fn handle_command(
+    state: &AppState,
+    user: &User,
+    conn: &mut Connection,
+    cmd: ClientFrame,
+) -> Vec<Message> {
+    // anything the client sends counts as activity
+    let ws_id = user.ws_id as u64;
+    state.broadcast_presence(ws_id, state.presence.touch(user.id as _, ws_id, Utc::now()));
+    match cmd {
+        ClientFrame::Subscribe { chat_ids } => {
+            conn.chat_ids = chat_ids.iter().copied().collect();
+            let mut frames = vec![to_text(&ServerFrame::Subscribed {
+                chat_ids: &chat_ids,
+            })];
+            // the events the client hasn't acked yet, later ones are still on their way,
+            // the client skips the ids it already has
+            match state.events_after(user.id as _, conn.acked) {
+                Ok(events) => frames.extend(
+                    events
+                        .iter()
+                        .filter(|e| e.id <= conn.last_id && conn.wants(&e.event))
+                        .map(event_frame),
+                ),
+                Err(_) => frames.push(resync_frame()),
+            }
+            frames
+        }
+        ClientFrame::Ack { id } => {
+            conn.acked = conn.acked.max(id.min(conn.last_id));
+            vec![]
+        }
+        ClientFrame::Typing { chat_id } => match state.send_typing(user, chat_id).await {
+            Ok(()) => vec![],
+            Err(e) => vec![to_text(&ServerFrame::Error {
+                message: e.to_string(),
+            })],
+        },
+        ClientFrame::Ping => vec![to_text(&ServerFrame::Pong)],
+    }
+}
+
+fn event_frame(e: &LoggedEvent) -> Message {
+    to_text(&ServerFrame::Event {
+        id: Some(e.id),
+        data: &e.event,
+    })
+}
+
+fn resync_frame() -> Message {
+    to_text(&ServerFrame::Event {
+        id: None,
+        data: &AppEvent::ResyncRequired,
+    })
+}
+
+fn to_text(frame: &ServerFrame) -> Message {
+    let v = serde_json::to_string(frame).expect("Failed to serialize frame");
+    Message::Text(v.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Typing;
+    use chat_core::ChatRead;
+    use std::sync::Arc;
+
+    #[test]
+    fn client_frame_should_parse() {
+        let frame: ClientFrame =
+            serde_json::from_str(r#"{"type":"subscribe","chat_ids":[1,2]}"#).unwrap();
+        assert_eq!(
+            frame,
+            ClientFrame::Subscribe {
+                chat_ids: vec![1, 2]
+            }
+        );
+        let frame: ClientFrame = serde_json::from_str(r#"{"type":"ping"}"#).unwrap();
+        assert_eq!(frame, ClientFrame::Ping);
+        assert!(serde_json::from_str::<ClientFrame>(r#"{"type":"typing"}"#).is_err());
+    }
+
+    #[test]
+    fn subscription_should_filter_chat_events() {
+        let event = AppEvent::Typing(Typing {
+            chat_id: 1,
+            user_id: 2,
+            expires_at: Utc::now(),
+        });
+        let mut conn = Connection::default();
+        assert!(conn.wants(&event));
+        conn.chat_ids = [2].into();
+        assert!(!conn.wants(&event));
+        conn.chat_ids = [1, 2].into();
+        assert!(conn.wants(&event));
+
+        // read positions belong to a chat as well, filtered ones still move the position
+        let read = LoggedEvent {
+            id: 5,
+            event: Arc::new(AppEvent::ChatRead(ChatRead {
+                chat_id: 3,
+                user_id: 2,
+                last_read_id: 1,
+                updated_at: Utc::now(),
+            })),
+        };
+        assert!(conn.pass(&read).is_none());
+        assert_eq!(conn.last_id, 5);
+        conn.chat_ids = [3].into();
+        assert!(conn.pass(&read).is_some());
+
+        let v = serde_json::to_value(ServerFrame::Event {
+            id: Some(1),
+            data: &event,
+        })
+        .unwrap();
+        assert_eq!(v["type"], "event");
+        assert_eq!(v["id"], 1);
+        assert_eq!(v["data"]["event"], "Typing");
+    }
+}